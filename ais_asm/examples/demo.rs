@@ -8,6 +8,8 @@ use std::fs::File;
 use std::io::Write;
 use std::process::Command;
 
+// Fields are only read through the Debug print when main() returns an error
+#[allow(dead_code)]
 #[derive(Debug)]
 enum TopError {
     DynAsmError(DynAsmError),
@@ -1,14 +1,15 @@
 extern crate ais_asm;
 
-use ais_asm::ais::{AddrSize, Const, Offset, Opcode, Register, Size};
+use ais_asm::ais::{AddrSize, Cond, Const, Cp2Reg, Offset, Register, Size};
 use ais_asm::asm;
-use ais_asm::decode::decode;
 use ais_asm::dynasm::{DynAsm, DynAsmError};
 
 use std::fs::File;
 use std::io::Write;
 use std::process::Command;
 
+// Fields are only read through the Debug print when main() returns an error
+#[allow(dead_code)]
 #[derive(Debug)]
 enum TopError {
     DynAsmError(DynAsmError),
@@ -60,7 +61,7 @@ fn dump_cp2_regs(asm: &mut DynAsm) -> Result<(), TopError> {
 
     asm.gen_load(edx, 0x50_0000 - 4)?;
     for i in 0..32 {
-        asm.gen(asm::cfc2(eax, Register(i)))?;
+        asm.gen(asm::cfc2(eax, Cp2Reg(i)))?;
 
         asm.gen(asm::push(Size::Bits32, eax, edx, Offset::Number(4)))?;
     }
@@ -101,6 +102,7 @@ fn dump_constant(asm: &mut DynAsm) -> Result<(), TopError> {
     let edx: Register = Register::EDX;
     let r0: Register = Register::R0;
 
+    // Results are stored from 0x50_0000, confirmed entries go into ais::CONST_OBSERVED
     asm.gen_load(edx, 0x50_0000 - 4)?;
     for i in 0..32 {
         asm.gen(asm::addi(eax, r0, Const::Raw(i)))?;
@@ -139,7 +141,7 @@ fn test_eflags(asm: &mut DynAsm) -> Result<(), TopError> {
         asm.gen(asm::add(eax, r4, r5))?;
         asm.gen(asm::add(eax, r4, r5))?;
 
-        asm.gen(asm::cfc2(eax, Register(31)))?;
+        asm.gen_read_eflags(eax)?;
         asm.gen(asm::push(Size::Bits32, eax, edx, Offset::Number(4)))?;
     }
 
@@ -179,10 +181,8 @@ fn test_xj(asm: &mut DynAsm) -> Result<(), TopError> {
 
             asm.gen(asm::add(r5, r6, r7))?;
 
-            let mut xj = asm::j(r4);
-            xj.opcode = Opcode::XJ;
-            xj.leftovers = 6 << 11 | i << 2;
-            asm.gen(xj)?;
+            let cond: Cond = num::FromPrimitive::from_u32(i).unwrap();
+            asm.gen(asm::jcc(cond, r4))?;
             asm.gen(asm::or(eax, eax, ecx))?;
             asm.set_sym_here(jmp)?;
         }
@@ -217,6 +217,22 @@ fn test_cond_jump(asm: &mut DynAsm) -> Result<(), TopError> {
     Ok(())
 }
 
+fn test_while_nonzero(asm: &mut DynAsm) -> Result<(), TopError> {
+    let eax: Register = Register::EAX;
+    let ecx: Register = Register::ECX;
+
+    // Same as test_cond_jump, but with the structured control flow builder
+    asm.gen_load(eax, 0)?;
+    asm.gen_load(ecx, 0b111111)?;
+
+    asm.while_nonzero(ecx, |asm| {
+        asm.gen(asm::addi(eax, eax, Const::Number(1)))?;
+        asm.gen(asm::shri(ecx, ecx, Const::Number(1)))
+    })?;
+
+    Ok(())
+}
+
 fn test_call_ret(asm: &mut DynAsm) -> Result<(), TopError> {
     let eax: Register = Register::EAX;
     let ecx: Register = Register::ECX;
@@ -249,8 +265,8 @@ fn test_timestamp(asm: &mut DynAsm) -> Result<(), TopError> {
 
     asm.gen_load(edx, 0x50_0000 - 4)?;
     for _ in 0..16 {
-        asm.gen(asm::cfc2(eax, Register(19)))?;
-        asm.gen(asm::cfc2(ecx, Register(19)))?;
+        asm.gen(asm::cfc2(eax, Cp2Reg::TSC_L))?;
+        asm.gen(asm::cfc2(ecx, Cp2Reg::TSC_L))?;
         asm.gen(asm::push(Size::Bits32, eax, edx, Offset::Number(4)))?;
         asm.gen(asm::push(Size::Bits32, ecx, edx, Offset::Number(4)))?;
     }
@@ -258,22 +274,34 @@ fn test_timestamp(asm: &mut DynAsm) -> Result<(), TopError> {
     Ok(())
 }
 
-fn dump_datasheet_constants() {
-    let entry = decode(&[0x62, 0x80, 0x19, 0x08, 0xE0, 0x83]);
-    let exit = decode(&[0x62, 0x80, 0x47, 0x00, 0x10, 0x18]);
-    println!("entry: {:?}", entry);
-    println!("exit:  {:?}", exit);
+// Results of the multiply and divide sub-ops, their semantics are not documented
+fn test_mul(asm: &mut DynAsm) -> Result<(), TopError> {
+    let eax: Register = Register::EAX;
+    let ecx: Register = Register::ECX;
+    let edx: Register = Register::EDX;
+    let ebx: Register = Register::EBX;
+
+    asm.gen_load(edx, 0x50_0000 - 4)?;
+    asm.gen_load(ecx, 0x1234_5678)?;
+    asm.gen_load(ebx, 0x100)?;
+
+    let instrs = [
+        asm::mul(eax, ecx, ebx),
+        asm::mflou(eax),
+        asm::mfloi(eax),
+        asm::imul(eax, ecx, ebx),
+        asm::idiv(eax, ecx, ebx),
+        asm::mflou(eax),
+    ];
+    for instr in instrs {
+        asm.gen(instr)?;
+        asm.gen(asm::push(Size::Bits32, eax, edx, Offset::Number(4)))?;
+    }
 
-    let eflags_load = decode(&[0x62, 0x80, 0xC0, 0xFF, 0x07, 0xA0]);
-    let eflags_store = decode(&[0x62, 0x80, 0x19, 0xF8, 0xE0, 0x80]);
-    println!("eflags_load:  {:?}", eflags_load);
-    println!("eflags_store: {:?}", eflags_store);
+    Ok(())
 }
 
 fn main() -> Result<(), TopError> {
-    // Decode various constants found in datasheet
-    dump_datasheet_constants();
-
     // Gen some code, at location 0x480000, this is where our kernel will place the payload
     let mut asm = DynAsm::new(0x48_0000);
 
@@ -289,8 +317,10 @@ fn main() -> Result<(), TopError> {
     dump_offset(&mut asm)?;
     dump_constant(&mut asm)?;
     test_cond_jump(&mut asm)?;
+    test_while_nonzero(&mut asm)?;
     test_call_ret(&mut asm)?;
     test_timestamp(&mut asm)?;
+    test_mul(&mut asm)?;
 
     // Append footer and we are done. This is just a return, so it will return from the payload back into the kernel
     asm.gen_footer();
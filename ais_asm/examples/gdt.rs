@@ -0,0 +1,63 @@
+extern crate ais_asm;
+
+use ais_asm::ais::{Offset, Register, Sel, Size};
+use ais_asm::asm;
+use ais_asm::dynasm::{DynAsm, DynAsmError};
+
+use std::fs::File;
+use std::io::Write;
+
+// Fields are only read through the Debug print when main() returns an error
+#[allow(dead_code)]
+#[derive(Debug)]
+enum TopError {
+    DynAsmError(DynAsmError),
+    IoError(std::io::Error),
+}
+
+impl From<DynAsmError> for TopError {
+    fn from(x: DynAsmError) -> Self {
+        Self::DynAsmError(x)
+    }
+}
+
+impl From<std::io::Error> for TopError {
+    fn from(x: std::io::Error) -> Self {
+        Self::IoError(x)
+    }
+}
+
+// Copy the first 16 GDT entries into the kernel register dump at 0x50_0000, the kernel prints its
+// own GDT next to it
+fn read_gdt(asm: &mut DynAsm) -> Result<(), TopError> {
+    let eax: Register = Register::EAX;
+    let ecx: Register = Register::ECX;
+    let edx: Register = Register::EDX;
+
+    asm.gen_load(edx, 0x50_0000 - 4)?;
+    for index in 0..16 {
+        asm.gen_read_desc(eax, ecx, Sel::GDT, index)?;
+        asm.gen(asm::push(Size::Bits32, eax, edx, Offset::Number(4)))?;
+        asm.gen(asm::push(Size::Bits32, ecx, edx, Offset::Number(4)))?;
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), TopError> {
+    // Gen some code, at location 0x480000, this is where our kernel will place the payload
+    let mut asm = DynAsm::new(0x48_0000);
+
+    asm.gen_header();
+    read_gdt(&mut asm)?;
+    asm.gen_footer();
+
+    asm.dump();
+
+    // Write payload to out.bin, the kernel will included this as the payload
+    let mut output = File::create("out.bin")?;
+    output.by_ref().write_all(asm.memory())?;
+    output.flush()?;
+
+    Ok(())
+}
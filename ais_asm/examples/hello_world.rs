@@ -8,6 +8,8 @@ use std::fs::File;
 use std::io::Write;
 use std::process::Command;
 
+// Fields are only read through the Debug print when main() returns an error
+#[allow(dead_code)]
 #[derive(Debug)]
 enum TopError {
     DynAsmError(DynAsmError),
@@ -89,3 +91,32 @@ fn main() -> Result<(), TopError> {
 
     Ok(())
 }
+
+// Interprets the payload and checks the UART accesses
+#[test]
+#[cfg(feature = "unconfirmed")]
+fn prints_over_com1() {
+    use ais_asm::sim::{IoAccess, IoLog, Sim, Stop, Uart16550};
+
+    let mut asm = DynAsm::new(0x48_0000);
+    hello_world(&mut asm).unwrap();
+    asm.gen_footer();
+
+    let mut sim = Sim::new(IoLog::new(Uart16550::new(0x3F8)));
+    sim.load(0x48_0000, asm.memory());
+    sim.set_reg(Register::ESP, 0x10_0000);
+    assert!(matches!(sim.run(0x48_0000, 10_000).unwrap(), Stop::X86(_)));
+
+    // Poll LSR once, the model is always ready, then write THR
+    let expected: Vec<IoAccess> = "Hello World!\n"
+        .bytes()
+        .flat_map(|b| {
+            [
+                IoAccess::Read(0x3FD, 0x60),
+                IoAccess::Write(0x3F8, b.into()),
+            ]
+        })
+        .collect();
+    assert_eq!(sim.io.log, expected);
+    assert_eq!(sim.io.io.tx, b"Hello World!\n");
+}
@@ -0,0 +1,51 @@
+extern crate ais_asm;
+
+use ais_asm::ais::Register;
+use ais_asm::dynasm::{DynAsm, DynAsmError};
+use ais_asm::stdlib::{self, COM1};
+
+use std::fs::File;
+use std::io::Write;
+
+// Fields are only read through the Debug print when main() returns an error
+#[allow(dead_code)]
+#[derive(Debug)]
+enum TopError {
+    DynAsmError(DynAsmError),
+    IoError(std::io::Error),
+}
+
+impl From<DynAsmError> for TopError {
+    fn from(x: DynAsmError) -> Self {
+        Self::DynAsmError(x)
+    }
+}
+
+impl From<std::io::Error> for TopError {
+    fn from(x: std::io::Error) -> Self {
+        Self::IoError(x)
+    }
+}
+
+// Sends 0xA5 through COM1 in loopback mode with XIOW and reads it back with XIOR. The kernel prints
+// the result as EAX, 0xA5 when both work and 0xFFFFFFFF when nothing came back.
+fn main() -> Result<(), TopError> {
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.gen_header();
+
+    let start = asm.new_sym();
+    asm.gen_jump(start)?;
+    let loopback = stdlib::gen_uart_loopback(&mut asm, COM1)?;
+    asm.set_sym_here(start)?;
+    asm.gen_load(Register::ECX, 0xA5)?;
+    asm.gen_call(loopback)?;
+
+    asm.gen_footer();
+    asm.dump();
+
+    let mut output = File::create("out.bin")?;
+    output.by_ref().write_all(asm.memory())?;
+    output.flush()?;
+
+    Ok(())
+}
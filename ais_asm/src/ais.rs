@@ -53,6 +53,7 @@ XRET, XCNULL, XRFP, XHALT SBF/SBN, XTI, XTII, XMDB, XMDBI
 
 */
 
+use crate::spec::{self, FormatSpec};
 use num_derive::FromPrimitive;
 use std::convert::TryFrom;
 
@@ -65,7 +66,7 @@ pub enum AisError {
     Unsupported(Field),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Field {
     Opcode,
     Const,
@@ -126,6 +127,18 @@ impl TryInto<u8> for Register {
     }
 }
 
+// CP2 control registers. EFLAGS is used by the datasheet load and store vectors, TSC_L was found
+// with the test_timestamp experiment. TSC_U, CR0 and NSIP from the notes have not been located,
+// dump_cp2_regs reads all 32 to look for them. Only CTC2 and CFC2 have builders, the MTC2 and
+// MFC2 sub-functions of XMISC are not known.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cp2Reg(pub u8);
+
+impl Cp2Reg {
+    pub const TSC_L: Self = Self(19);
+    pub const EFLAGS: Self = Self(31);
+}
+
 #[test]
 fn register_from_into_identity() {
     for i in 0..32u8 {
@@ -135,22 +148,70 @@ fn register_from_into_identity() {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Const {
     Number(i8),
-    // There are some other special case, skip for now
+    // Operand size dependent values, named as in the Offset table
+    OS,
+    PDOS,
+    MOS,
+    MGS,
+    MDOS,
+    DF,
+    DFOS,
+    DISP,
+    // Encodings that have not been identified yet
     Raw(u8),
 }
 
+// Encodings seen on hardware with the dump_constant experiment, (encoding, value)
+pub const CONST_OBSERVED: &[(u8, i8)] = &[(0b00000, 0), (0b00001, 1), (0b01111, 5), (0b10010, 6)];
+
+// The observed encodings for 0, 1 and 5 are the same as in the Offset table, so the rest of the
+// Offset table is assumed to be shared as well. None of it has been confirmed on hardware, so it
+// is part of the unconfirmed feature. Without it these encodings are Raw.
+#[cfg(feature = "unconfirmed")]
+const CONST_UNCONFIRMED: &[(u8, Const)] = &[
+    (0b00010, Const::Number(2)),
+    (0b00011, Const::Number(4)),
+    (0b00100, Const::Number(8)),
+    (0b00101, Const::Number(16)),
+    (0b00110, Const::Number(24)),
+    (0b00111, Const::Number(32)),
+    (0b01000, Const::Number(10)),
+    (0b01001, Const::Number(-1)),
+    (0b01010, Const::Number(-2)),
+    (0b01011, Const::Number(-4)),
+    (0b01100, Const::Number(-8)),
+    (0b10000, Const::OS),
+    (0b10001, Const::PDOS),
+    (0b11000, Const::MOS),
+    (0b11001, Const::MGS),
+    (0b11010, Const::MDOS),
+    (0b11100, Const::DF),
+    (0b11101, Const::DFOS),
+    (0b11111, Const::DISP),
+];
+
+#[cfg(not(feature = "unconfirmed"))]
+const CONST_UNCONFIRMED: &[(u8, Const)] = &[];
+
 impl TryFrom<u8> for Const {
     type Error = ();
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        Ok(match value {
-            0b00000 => Self::Number(0),
-            x if x < 32 => Self::Raw(x),
-            _ => return Err(()),
-        })
+        if value > 0b11111 {
+            return Err(());
+        }
+        if let Some(&(_, x)) = CONST_OBSERVED.iter().find(|x| x.0 == value) {
+            return Ok(Self::Number(x));
+        }
+
+        Ok(CONST_UNCONFIRMED
+            .iter()
+            .find(|x| x.0 == value)
+            .map_or(Self::Raw(value), |x| x.1))
     }
 }
 
@@ -158,18 +219,21 @@ impl TryInto<u8> for Const {
     type Error = ();
 
     fn try_into(self) -> Result<u8, Self::Error> {
-        Ok(match self {
-            Const::Number(0) => 0b00000,
-            Const::Number(1) => 0b00001,
-
-            Const::Number(5) => 0b01111,
-
-            Const::Number(6) => 0b10010,
+        if let Const::Number(value) = self {
+            if let Some(&(bits, _)) = CONST_OBSERVED.iter().find(|x| x.1 == value) {
+                return Ok(bits);
+            }
+        }
 
-            Const::Raw(x) if x <= 0b11111 => x,
-            Const::Raw(_) => return Err(()),
-            _ => todo!(),
-        })
+        match self {
+            Const::Raw(x) if x <= 0b11111 => Ok(x),
+            Const::Raw(_) => Err(()),
+            _ => CONST_UNCONFIRMED
+                .iter()
+                .find(|x| x.1 == self)
+                .map(|x| x.0)
+                .ok_or(()),
+        }
     }
 }
 
@@ -182,6 +246,38 @@ fn const_from_into_identity() {
     }
 }
 
+#[test]
+fn const_matches_hardware() {
+    for &(bits, value) in CONST_OBSERVED {
+        assert_eq!(Const::try_from(bits), Ok(Const::Number(value)));
+        assert_eq!(Const::Number(value).try_into(), Ok(bits));
+    }
+    assert_eq!(TryInto::<u8>::try_into(Const::Number(3)), Err(()));
+}
+
+#[test]
+#[cfg(feature = "unconfirmed")]
+fn offset_table_consts_encode() {
+    let bits: u8 = Const::Number(4).try_into().unwrap();
+    assert_eq!(bits, 0b00011);
+    assert!(
+        crate::asm::addi(Register::EAX, Register::EAX, Const::Number(4))
+            .encode()
+            .is_ok()
+    );
+}
+
+#[test]
+fn unconfirmed_consts_follow_the_feature() {
+    let enabled = cfg!(feature = "unconfirmed");
+
+    let encodes = |c: Const| TryInto::<u8>::try_into(c).is_ok();
+    assert_eq!(encodes(Const::Number(4)), enabled);
+    assert_eq!(encodes(Const::DFOS), enabled);
+    assert_eq!(Const::try_from(0b00011) == Ok(Const::Number(4)), enabled);
+    assert_eq!(Const::try_from(0b11101) == Ok(Const::DFOS), enabled);
+}
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone)]
 pub enum Offset {
@@ -344,9 +440,35 @@ impl TryInto<u8> for SubOp {
     }
 }
 
-#[derive(Debug, Copy, Clone, FromPrimitive)]
+// Only Norm is named in the notes, the other sub-ops decode as Raw
+#[derive(Debug, Copy, Clone)]
 pub enum SubOpXio {
-    Norm = 0,
+    Norm,
+    Raw(u8),
+}
+
+impl TryFrom<u8> for SubOpXio {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Norm,
+            x if x < 4 => Self::Raw(x),
+            _ => return Err(()),
+        })
+    }
+}
+
+impl TryInto<u8> for SubOpXio {
+    type Error = ();
+
+    fn try_into(self) -> Result<u8, Self::Error> {
+        Ok(match self {
+            Self::Norm => 0,
+            Self::Raw(x) if x != 0 && x < 4 => x,
+            _ => return Err(()),
+        })
+    }
 }
 
 #[derive(Debug, Copy, Clone, FromPrimitive)]
@@ -362,7 +484,7 @@ pub enum Function {
     Xio(SubOpXio, AddrSize, Size, Sel),
     Xls(SubOp, AddrSize, Size, Sel),
     Xalu(SubOpXalu, DpCntl),
-    Xj(XjSize, XjMode),
+    Xj(XjSize, XjCond, XjMode),
     Xlea(AddrSize, Size),
     Xmisc(SubFunc, u8),
     Raw(u16),
@@ -387,6 +509,46 @@ pub enum XjMode {
     X86 = 0b11,
 }
 
+// Conditional jumps are not documented. The condition is placed in IIR.tttn (bits 5:2) and uses the x86 tttn encoding.
+// The value in bits 15:11 that enables the condition was found with the test_xj experiment.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum XjCond {
+    Always,
+    If(Cond),
+    Raw(u16), // Any other value of bits 15:11 and 5:2, as bits 15:11 << 4 | tttn
+}
+
+pub const XJ_COND_ENABLE: u32 = 0b00110;
+pub const XJ_TTTN_ALWAYS: u32 = 0b0001;
+
+// x86 tttn condition encoding
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+pub enum Cond {
+    O = 0b0000,  // Overflow
+    NO = 0b0001, // No overflow
+    C = 0b0010,  // Carry, below
+    NC = 0b0011, // No carry, above or equal
+    Z = 0b0100,  // Zero, equal
+    NZ = 0b0101, // Not zero, not equal
+    BE = 0b0110, // Below or equal
+    A = 0b0111,  // Above
+    S = 0b1000,  // Sign
+    NS = 0b1001, // No sign
+    P = 0b1010,  // Parity even
+    NP = 0b1011, // Parity odd
+    L = 0b1100,  // Less
+    GE = 0b1101, // Greater or equal
+    LE = 0b1110, // Less or equal
+    G = 0b1111,  // Greater
+}
+
+impl Cond {
+    pub fn negate(self) -> Self {
+        num::FromPrimitive::from_u8(self as u8 ^ 1).unwrap()
+    }
+}
+
 #[derive(Debug, Copy, Clone, FromPrimitive)]
 pub enum DpCntl {
     Word = 0b000,
@@ -473,14 +635,7 @@ pub enum SubOpXalu {
 
 #[derive(Debug, Copy, Clone)]
 pub struct Instruction {
-    // Formats
-    //        31:26    25:21    20:16   15:11    10:0
-    // I:     Opcode | RS     | RT    | Immediate
-    // XALU:  Opcode | RS     | RT    | RD     | Function
-    // XALUI: Opcode | RS     | Const | RD     | Function
-    // XMISC: Opcode | RS     | RT    | RD     | Function
-    // XLS:   Opcode | RS     | Base  | Offset | Function  - Wrong!
-    // XLS:   Opcode | Offset | Base  | RS     | Function  - Correct
+    // Formats and bit layouts are described in spec
     pub opcode: Opcode,
     pub rs: Option<Register>,
     pub rt: Option<Register>, // Base
@@ -507,33 +662,28 @@ impl Instruction {
         }
     }
 
+    pub fn format(&self) -> Option<&'static FormatSpec> {
+        spec::format(self.opcode)
+    }
+
+    fn format_is(&self, names: &[&str]) -> bool {
+        self.format().is_some_and(|x| names.contains(&x.name))
+    }
+
     pub fn is_i_type(&self) -> bool {
-        matches!(
-            self.opcode,
-            Opcode::ORIU
-                | Opcode::ADDI
-                | Opcode::ANDIU
-                | Opcode::ANDIL
-                | Opcode::ANDI
-                | Opcode::ORI
-                | Opcode::XORI
-                | Opcode::XORIU
-        )
+        self.format_is(&["I"])
     }
 
     pub fn is_xalu_type(&self) -> bool {
-        matches!(self.opcode, Opcode::XALU | Opcode::XALUR)
+        self.format_is(&["XALU"])
     }
 
     pub fn is_xalui_type(&self) -> bool {
-        matches!(self.opcode, Opcode::XALUI | Opcode::XALUIR)
+        self.format_is(&["XALUI"])
     }
 
     pub fn is_xls_type(&self) -> bool {
-        matches!(
-            self.opcode,
-            Opcode::XIOR | Opcode::XIOW | Opcode::XPUSH | Opcode::XPOP | Opcode::XPUSHIP
-        )
+        self.format_is(&["XLS", "XIO"])
     }
 
     pub fn encode(&self) -> Result<Vec<u8>, AisError> {
@@ -1,85 +1,179 @@
 use crate::ais::{
-    AddrSize, Const, DpCntl, Function, Instruction, Offset, Opcode, Register, Sel, Size, SubFunc,
-    SubOp, SubOpXalu, SubOpXio, XjMode, XjSize,
+    AddrSize, Cond, Const, Cp2Reg, DpCntl, Field, Function, Instruction, Offset, Opcode, Register,
+    Sel, Size, SubFunc, SubOp, SubOpXalu, SubOpXio, XjCond, XjMode, XjSize,
 };
+use crate::spec;
+use crate::typed::{XioOpcode, XlsOpcode};
+
+// Operand of a builder, the spec format of the opcode decides which field it goes to
+#[derive(Debug, Copy, Clone)]
+enum Value {
+    Reg(Register),
+    Imm(u16),
+    Const(Const),
+    Offset(Offset),
+}
+
+// Instruction with operands in the assembly order of its format, e.g. rt, rs, imm for I type. The
+// public builders only pass opcodes of the right format, so a panic here is a bug in this file.
+fn build(opcode: Opcode, operands: &[Value], function: Option<Function>) -> Instruction {
+    let format = spec::format(opcode).expect("opcode without a known layout");
+    assert_eq!(format.operands.len(), operands.len(), "{:?}", opcode);
 
-fn i_type(opcode: Opcode, dst: Register, src: Register, imm: u16) -> Instruction {
     let mut ret = Instruction::new(opcode);
-    ret.rs = Some(src);
-    ret.rt = Some(dst);
-    ret.imm = Some(imm);
+    for (field, &value) in format.operands.iter().zip(operands) {
+        match (field.field, value) {
+            (Field::RS, Value::Reg(reg)) => ret.rs = Some(reg),
+            (Field::RT, Value::Reg(reg)) => ret.rt = Some(reg),
+            (Field::RD, Value::Reg(reg)) => ret.rd = Some(reg),
+            (Field::Immediate, Value::Imm(imm)) => ret.imm = Some(imm),
+            (Field::Const, Value::Const(constant)) => ret.constant = Some(constant),
+            (Field::Offset, Value::Offset(offset)) => ret.offset = Some(offset),
+            _ => panic!("{:?} {} can't be {:?}", opcode, field.name, value),
+        }
+    }
+    ret.function = function;
     ret
 }
 
-fn xalur(
-    subop: SubOpXalu,
-    dpcntl: DpCntl,
+fn i_type(opcode: Opcode, dst: Register, src: Register, imm: u16) -> Instruction {
+    let operands = [Value::Reg(dst), Value::Reg(src), Value::Imm(imm)];
+    build(opcode, &operands, None)
+}
+
+// Whether an XALU instruction updates EFLAGS, XALUR/XALUIR do and XALU/XALUI don't
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Flags {
+    Keep,
+    Update,
+}
+
+// Second XALU operand, a register selects XALU/XALUR and a constant XALUI/XALUIR
+#[derive(Debug, Copy, Clone)]
+pub enum Operand {
+    Reg(Register),
+    Const(Const),
+}
+
+impl From<Register> for Operand {
+    fn from(x: Register) -> Self {
+        Operand::Reg(x)
+    }
+}
+
+impl From<Const> for Operand {
+    fn from(x: Const) -> Self {
+        Operand::Const(x)
+    }
+}
+
+// Any XALU sub-op, the opcode follows from the operand and flags
+pub fn alu(
+    sub_op: SubOpXalu,
+    dp_cntl: DpCntl,
+    flags: Flags,
     dst: Register,
     src: Register,
-    extra: Register,
+    operand: impl Into<Operand>,
 ) -> Instruction {
-    let mut ret = Instruction::new(Opcode::XALUR);
-    ret.rs = Some(src);
-    ret.rd = Some(dst);
-    ret.rt = Some(extra);
-    ret.function = Some(Function::Xalu(subop, dpcntl));
-    ret
+    let operand = operand.into();
+    let opcode = match (operand, flags) {
+        (Operand::Reg(_), Flags::Keep) => Opcode::XALU,
+        (Operand::Reg(_), Flags::Update) => Opcode::XALUR,
+        (Operand::Const(_), Flags::Keep) => Opcode::XALUI,
+        (Operand::Const(_), Flags::Update) => Opcode::XALUIR,
+    };
+
+    let operand = match operand {
+        Operand::Reg(reg) => Value::Reg(reg),
+        Operand::Const(constant) => Value::Const(constant),
+    };
+    let operands = [Value::Reg(dst), Value::Reg(src), operand];
+    build(opcode, &operands, Some(Function::Xalu(sub_op, dp_cntl)))
 }
 
-fn xaluir(
-    subop: SubOpXalu,
-    dpcntl: DpCntl,
-    dst: Register,
-    src: Register,
-    constant: Const,
+fn xls_type(
+    opcode: Opcode,
+    rs: Register,
+    base: Register,
+    offset: Offset,
+    function: Function,
 ) -> Instruction {
-    let mut ret = Instruction::new(Opcode::XALUIR);
-    ret.rs = Some(src);
-    ret.rd = Some(dst);
-    ret.constant = Some(constant);
-    ret.function = Some(Function::Xalu(subop, dpcntl));
-    ret
+    let operands = [Value::Reg(rs), Value::Reg(base), Value::Offset(offset)];
+    build(opcode, &operands, Some(function))
 }
 
-fn xls_type(opcode: Opcode, rs: Register, base: Register, offset: Offset) -> Instruction {
-    let mut ret = Instruction::new(opcode);
-    ret.rs = Some(rs);
-    ret.rt = Some(base);
-    ret.offset = Some(offset);
-    ret
+// Port I/O at port + offset. The function field is assumed to be laid out like the XLS one, this
+// has not been checked against the reference. The reference does not say how AddrSize and Sel
+// apply to ports, ior and iow use a 16bit address like x86 in/out.
+pub fn xio(
+    opcode: XioOpcode,
+    sub_op: SubOpXio,
+    size: Size,
+    port: Register,
+    offset: Offset,
+    value: Register,
+) -> Instruction {
+    let function = Function::Xio(sub_op, AddrSize::Bits16, size, Sel::FLAT);
+    xls_type(opcode.into(), value, port, offset, function)
 }
 
 pub fn iow(size: Size, port: Register, value: Register) -> Instruction {
-    let mut instr = xls_type(Opcode::XIOW, value, port, Offset::Number(0));
-    instr.function = Some(Function::Xio(
-        SubOpXio::Norm,
-        AddrSize::Bits16,
-        size,
-        Sel::FLAT,
-    ));
-    instr
+    let zero = Offset::Number(0);
+    xio(XioOpcode::XIOW, SubOpXio::Norm, size, port, zero, value)
 }
 
 pub fn ior(size: Size, port: Register, value: Register) -> Instruction {
-    let mut instr = xls_type(Opcode::XIOR, value, port, Offset::Number(0));
-    instr.function = Some(Function::Xio(
-        SubOpXio::Norm,
-        AddrSize::Bits16,
-        size,
-        Sel::FLAT,
-    ));
-    instr
+    let zero = Offset::Number(0);
+    xio(XioOpcode::XIOR, SubOpXio::Norm, size, port, zero, value)
+}
+
+pub fn iow8(port: Register, value: Register) -> Instruction {
+    iow(Size::Bits8L, port, value)
+}
+
+pub fn iow16(port: Register, value: Register) -> Instruction {
+    iow(Size::Bits16, port, value)
+}
+
+pub fn iow32(port: Register, value: Register) -> Instruction {
+    iow(Size::Bits32, port, value)
+}
+
+pub fn ior8(port: Register, value: Register) -> Instruction {
+    ior(Size::Bits8L, port, value)
+}
+
+pub fn ior16(port: Register, value: Register) -> Instruction {
+    ior(Size::Bits16, port, value)
+}
+
+pub fn ior32(port: Register, value: Register) -> Instruction {
+    ior(Size::Bits32, port, value)
+}
+
+#[test]
+#[cfg(feature = "unconfirmed")]
+fn xio_decode_identity() {
+    let (edx, eax) = (Register::EDX, Register::EAX);
+    for sub_op in (0..4).map(|x| SubOpXio::try_from(x).unwrap()) {
+        for opcode in [XioOpcode::XIOR, XioOpcode::XIOW] {
+            let instr = xio(opcode, sub_op, Size::Bits16, edx, Offset::Number(4), eax);
+            let (decoded, _) = Instruction::decode(&instr.encode().unwrap()).unwrap();
+            assert_eq!(decoded.opcode, opcode.into());
+            assert_eq!((decoded.rs, decoded.rt), (Some(eax), Some(edx)));
+            assert_eq!(
+                format!("{:?}", decoded.function),
+                format!("{:?}", instr.function)
+            );
+            assert_eq!(decoded.leftovers, 0);
+        }
+    }
 }
 
 pub fn push(size: Size, reg: Register, base: Register, offset: Offset) -> Instruction {
-    let mut instr = xls_type(Opcode::XPUSH, reg, base, offset);
-    instr.function = Some(Function::Xls(
-        SubOp::Raw(0),
-        AddrSize::Bits32,
-        size,
-        Sel::SS,
-    ));
-    instr
+    let function = Function::Xls(SubOp::Raw(0), AddrSize::Bits32, size, Sel::SS);
+    xls_type(Opcode::XPUSH, reg, base, offset, function)
 }
 
 pub fn pushsp(size: Size, reg: Register) -> Instruction {
@@ -87,14 +181,8 @@ pub fn pushsp(size: Size, reg: Register) -> Instruction {
 }
 
 pub fn pop(size: Size, reg: Register, base: Register, offset: Offset) -> Instruction {
-    let mut instr = xls_type(Opcode::XPOP, reg, base, offset);
-    instr.function = Some(Function::Xls(
-        SubOp::Raw(0),
-        AddrSize::Bits32,
-        size,
-        Sel::SS,
-    ));
-    instr
+    let function = Function::Xls(SubOp::Raw(0), AddrSize::Bits32, size, Sel::SS);
+    xls_type(Opcode::XPOP, reg, base, offset, function)
 }
 
 pub fn popsp(size: Size, reg: Register) -> Instruction {
@@ -102,19 +190,113 @@ pub fn popsp(size: Size, reg: Register) -> Instruction {
 }
 
 pub fn puship(size: Size) -> Instruction {
-    let mut instr = xls_type(
+    let function = Function::Xls(SubOp::Raw(0), AddrSize::Bits32, size, Sel::SS);
+    let offset = Offset::Number(-4);
+    xls_type(
         Opcode::XPUSHIP,
         Register::R0,
         Register::ESP,
-        Offset::Number(-4),
-    );
-    instr.function = Some(Function::Xls(
-        SubOp::Raw(0),
-        AddrSize::Bits32,
+        offset,
+        function,
+    )
+}
+
+// Any opcode of the XL and XS families. XL and XS are plain loads and stores, what sets XL2, XL3,
+// XLBI, XS2, XSI and XSU apart is not known.
+pub fn xls(
+    opcode: XlsOpcode,
+    size: Size,
+    reg: Register,
+    base: Register,
+    offset: Offset,
+    sel: Sel,
+    addr_size: AddrSize,
+) -> Instruction {
+    let function = Function::Xls(SubOp::Raw(0), addr_size, size, sel);
+    xls_type(opcode.into(), reg, base, offset, function)
+}
+
+// dst = [sel:base + offset]
+pub fn load(
+    size: Size,
+    dst: Register,
+    base: Register,
+    offset: Offset,
+    sel: Sel,
+    addr_size: AddrSize,
+) -> Instruction {
+    xls(XlsOpcode::XL, size, dst, base, offset, sel, addr_size)
+}
+
+// [sel:base + offset] = src
+pub fn store(
+    size: Size,
+    src: Register,
+    base: Register,
+    offset: Offset,
+    sel: Sel,
+    addr_size: AddrSize,
+) -> Instruction {
+    xls(XlsOpcode::XS, size, src, base, offset, sel, addr_size)
+}
+
+// Load the descriptor at [table:base + offset] into the hidden part of a segment register. This is
+// our reading of XLDESC, which is only named in the reference.
+pub fn ldesc(seg: Register, base: Register, offset: Offset, table: Sel) -> Instruction {
+    let size = Size::Bits64;
+    xls(
+        XlsOpcode::XLDESC,
         size,
-        Sel::SS,
-    ));
-    instr
+        seg,
+        base,
+        offset,
+        table,
+        AddrSize::Bits32,
+    )
+}
+
+#[test]
+fn load_store_decode_identity() {
+    use num::FromPrimitive;
+
+    let opcodes = [
+        XlsOpcode::XL,
+        XlsOpcode::XL2,
+        XlsOpcode::XL3,
+        XlsOpcode::XLBI,
+        XlsOpcode::XS,
+        XlsOpcode::XS2,
+        XlsOpcode::XSI,
+        XlsOpcode::XSU,
+        XlsOpcode::XLDESC,
+    ];
+    let sels = (0..16).filter_map(Sel::from_u8);
+    let sizes = (0..8).filter_map(Size::from_u8);
+
+    for (sel, size) in sels.flat_map(|sel| sizes.clone().map(move |size| (sel, size))) {
+        for opcode in opcodes {
+            let (eax, ebx) = (Register::EAX, Register::EBX);
+            let instr = xls(opcode, size, eax, ebx, Offset::OS, sel, AddrSize::Bits16);
+            let (decoded, _) = Instruction::decode(&instr.encode().unwrap()).unwrap();
+            assert_eq!(decoded.opcode, opcode.into());
+            assert_eq!((decoded.rs, decoded.rt), (Some(eax), Some(ebx)));
+            assert_eq!(
+                format!("{:?}", decoded.function),
+                format!("{:?}", instr.function)
+            );
+            assert_eq!(decoded.leftovers, 0);
+        }
+    }
+
+    let sas = load(
+        Size::SAS,
+        Register::EAX,
+        Register::EBX,
+        Offset::Number(0),
+        Sel::DS,
+        AddrSize::Bits32,
+    );
+    assert!(sas.encode().is_err());
 }
 
 pub fn lead(
@@ -124,11 +306,11 @@ pub fn lead(
     addr_size: AddrSize,
     size: Size,
 ) -> Instruction {
-    let mut instr = xls_type(Opcode::XLEAD, dst, base, offset);
-    instr.function = Some(Function::Xlea(addr_size, size));
-    instr
+    let function = Function::Xlea(addr_size, size);
+    xls_type(Opcode::XLEAD, dst, base, offset, function)
 }
 
+// XLEAI has no known layout in spec, so its fields are set by hand
 pub fn leai(
     dst: Register,
     base: Register,
@@ -144,20 +326,79 @@ pub fn leai(
     instr
 }
 
-pub fn cfc2(dst: Register, src: Register) -> Instruction {
-    let mut instr = Instruction::new(Opcode::XMISC);
-    instr.rt = Some(dst);
-    instr.rd = Some(src);
-    instr.function = Some(Function::Xmisc(SubFunc::CFC2, 0));
-    instr
+// Copy from a CP2 control register
+pub fn cfc2(dst: Register, src: Cp2Reg) -> Instruction {
+    let operands = [
+        Value::Reg(Register::R0),
+        Value::Reg(dst),
+        Value::Reg(Register(src.0)),
+    ];
+    build(
+        Opcode::XMISC,
+        &operands,
+        Some(Function::Xmisc(SubFunc::CFC2, 0)),
+    )
+}
+
+// Copy to a CP2 control register
+pub fn ctc2(dst: Cp2Reg, src: Register) -> Instruction {
+    let dst = Register(dst.0);
+    alu(
+        SubOpXalu::CTC2,
+        DpCntl::Word,
+        Flags::Keep,
+        dst,
+        src,
+        Register::R0,
+    )
+}
+
+#[test]
+fn cp2_matches_datasheet_eflags() {
+    let load = cfc2(Register(7), Cp2Reg::EFLAGS);
+    assert_eq!(load.encode().unwrap(), [0x62, 0x80, 0xC0, 0xFF, 0x07, 0xA0]);
+
+    let store = ctc2(Cp2Reg::EFLAGS, Register(7));
+    assert_eq!(
+        store.encode().unwrap(),
+        [0x62, 0x80, 0x19, 0xF8, 0xE0, 0x80]
+    );
+}
+
+pub fn xj(size: XjSize, cond: XjCond, mode: XjMode, base: Register) -> Instruction {
+    let function = Function::Xj(size, cond, mode);
+    build(Opcode::XJ, &[Value::Reg(base)], Some(function))
 }
 
 pub fn j(base: Register) -> Instruction {
-    let mut ret = Instruction::new(Opcode::XJ);
-    ret.rt = Some(base);
-    ret.function = Some(Function::Xj(XjSize::Bits32, XjMode::AIS));
-    ret.leftovers = 0b0001 << 2; // Use leftovers to set undocumented bit field
-    ret
+    xj(XjSize::Bits32, XjCond::Always, XjMode::AIS, base)
+}
+
+// Jump to base and switch back to x86 mode
+pub fn jx86(base: Register) -> Instruction {
+    xj(XjSize::Bits32, XjCond::Always, XjMode::X86, base)
+}
+
+// Jump to base when the condition holds for the current EFLAGS
+pub fn jcc(cond: Cond, base: Register) -> Instruction {
+    xj(XjSize::Bits32, XjCond::If(cond), XjMode::AIS, base)
+}
+
+#[test]
+fn jx86_matches_datasheet_exit() {
+    let bytes = jx86(Register::EAX).encode().unwrap();
+    assert_eq!(bytes, [0x62, 0x80, 0x47, 0x00, 0x10, 0x18]);
+}
+
+#[test]
+fn jcc_decode_identity() {
+    let bytes = jcc(Cond::NZ, Register::R4).encode().unwrap();
+    let (instr, _) = Instruction::decode(&bytes).unwrap();
+    assert!(matches!(
+        instr.function,
+        Some(Function::Xj(_, XjCond::If(Cond::NZ), XjMode::AIS))
+    ));
+    assert_eq!(instr.leftovers, 0);
 }
 
 pub fn xandil(dst: Register, src: Register, imm: u16) -> Instruction {
@@ -176,50 +417,392 @@ pub fn xoriu(dst: Register, src: Register, imm: u16) -> Instruction {
     i_type(Opcode::ORIU, dst, src, imm)
 }
 
+pub fn xaddi(dst: Register, src: Register, imm: u16) -> Instruction {
+    i_type(Opcode::ADDI, dst, src, imm)
+}
+
+pub fn xxori(dst: Register, src: Register, imm: u16) -> Instruction {
+    i_type(Opcode::XORI, dst, src, imm)
+}
+
+pub fn xxoriu(dst: Register, src: Register, imm: u16) -> Instruction {
+    i_type(Opcode::XORIU, dst, src, imm)
+}
+
 pub fn and(dst: Register, src: Register, extra: Register) -> Instruction {
-    xalur(SubOpXalu::AND, DpCntl::Word, dst, src, extra)
+    alu(SubOpXalu::AND, DpCntl::Word, Flags::Update, dst, src, extra)
 }
 
 pub fn andi(dst: Register, src: Register, constant: Const) -> Instruction {
-    xaluir(SubOpXalu::AND, DpCntl::Word, dst, src, constant)
+    alu(
+        SubOpXalu::AND,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
 }
 
 pub fn or(dst: Register, src: Register, extra: Register) -> Instruction {
-    xalur(SubOpXalu::OR, DpCntl::Word, dst, src, extra)
+    alu(SubOpXalu::OR, DpCntl::Word, Flags::Update, dst, src, extra)
 }
 
 pub fn ori(dst: Register, src: Register, constant: Const) -> Instruction {
-    xaluir(SubOpXalu::OR, DpCntl::Word, dst, src, constant)
+    alu(
+        SubOpXalu::OR,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
 }
 
 pub fn sub(dst: Register, src: Register, extra: Register) -> Instruction {
-    xalur(SubOpXalu::SUB, DpCntl::Word, dst, src, extra)
+    alu(SubOpXalu::SUB, DpCntl::Word, Flags::Update, dst, src, extra)
 }
 
 pub fn subi(dst: Register, src: Register, constant: Const) -> Instruction {
-    xaluir(SubOpXalu::SUB, DpCntl::Word, dst, src, constant)
+    alu(
+        SubOpXalu::SUB,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
 }
 
 pub fn add(dst: Register, src: Register, extra: Register) -> Instruction {
-    xalur(SubOpXalu::ADD, DpCntl::Word, dst, src, extra)
+    alu(SubOpXalu::ADD, DpCntl::Word, Flags::Update, dst, src, extra)
 }
 
 pub fn addi(dst: Register, src: Register, constant: Const) -> Instruction {
-    xaluir(SubOpXalu::ADD, DpCntl::Word, dst, src, constant)
+    alu(
+        SubOpXalu::ADD,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+// XALUI, leaves EFLAGS alone
+pub fn addi_nf(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::ADD,
+        DpCntl::Word,
+        Flags::Keep,
+        dst,
+        src,
+        constant,
+    )
 }
 
 pub fn shl(dst: Register, src: Register, extra: Register) -> Instruction {
-    xalur(SubOpXalu::SHL, DpCntl::Word, dst, src, extra)
+    alu(SubOpXalu::SHL, DpCntl::Word, Flags::Update, dst, src, extra)
 }
 
 pub fn shli(dst: Register, src: Register, constant: Const) -> Instruction {
-    xaluir(SubOpXalu::SHL, DpCntl::Word, dst, src, constant)
+    alu(
+        SubOpXalu::SHL,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
 }
 
 pub fn shr(dst: Register, src: Register, extra: Register) -> Instruction {
-    xalur(SubOpXalu::SHR, DpCntl::Word, dst, src, extra)
+    alu(SubOpXalu::SHR, DpCntl::Word, Flags::Update, dst, src, extra)
 }
 
 pub fn shri(dst: Register, src: Register, constant: Const) -> Instruction {
-    xaluir(SubOpXalu::SHR, DpCntl::Word, dst, src, constant)
+    alu(
+        SubOpXalu::SHR,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+pub fn sar(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::SAR, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn sari(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::SAR,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+pub fn rol(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::ROL, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn roli(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::ROL,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+pub fn ror(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::ROR, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn rori(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::ROR,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+pub fn rcl(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::RCL, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn rcli(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::RCL,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+pub fn rcr(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::RCR, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn rcri(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::RCR,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+// Like x86, INC and DEC leave CF alone
+pub fn inc(dst: Register, src: Register) -> Instruction {
+    alu(
+        SubOpXalu::INC,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        Register::R0,
+    )
+}
+
+pub fn dec(dst: Register, src: Register) -> Instruction {
+    alu(
+        SubOpXalu::DEC,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        Register::R0,
+    )
+}
+
+pub fn adc(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::ADC, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn adci(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::ADC,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+pub fn sbb(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::SBB, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn sbbi(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::SBB,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+// xori is the I type ORI, XOR with a constant goes through alu
+pub fn xor(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::XOR, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn nor(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::NOR, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn nori(dst: Register, src: Register, constant: Const) -> Instruction {
+    alu(
+        SubOpXalu::NOR,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        constant,
+    )
+}
+
+// CMPS and SETCC are only named in the reference. CMPS presumably compares like the string
+// instruction, and SETCC presumably sets dst from the condition of the translated instruction.
+pub fn cmps(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(
+        SubOpXalu::CMPS,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        extra,
+    )
+}
+
+pub fn setcc(dst: Register) -> Instruction {
+    let r0 = Register::R0;
+    alu(SubOpXalu::SETCC, DpCntl::Word, Flags::Keep, dst, r0, r0)
+}
+
+// IMUL, MUL, IDIV, MFLOU and MFLOI are only named in the reference. The notes put the results
+// in LO, HI and MD, how much of them ends up in dst is not known and HI can't be read yet.
+pub fn imul(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(
+        SubOpXalu::IMUL,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        extra,
+    )
+}
+
+pub fn mul(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(SubOpXalu::MUL, DpCntl::Word, Flags::Update, dst, src, extra)
+}
+
+pub fn idiv(dst: Register, src: Register, extra: Register) -> Instruction {
+    alu(
+        SubOpXalu::IDIV,
+        DpCntl::Word,
+        Flags::Update,
+        dst,
+        src,
+        extra,
+    )
+}
+
+pub fn mflou(dst: Register) -> Instruction {
+    let r0 = Register::R0;
+    alu(SubOpXalu::MFLOU, DpCntl::Word, Flags::Keep, dst, r0, r0)
+}
+
+pub fn mfloi(dst: Register) -> Instruction {
+    let r0 = Register::R0;
+    alu(SubOpXalu::MFLOI, DpCntl::Word, Flags::Keep, dst, r0, r0)
+}
+
+#[test]
+fn mul_div_encoding() {
+    let (eax, ecx, edx) = (Register::EAX, Register::ECX, Register::EDX);
+    let cases = [
+        (imul(eax, ecx, edx), [0x0C, 0x80, 0x32, 0x8A]),
+        (mul(eax, ecx, edx), [0x0D, 0x80, 0x32, 0x8A]),
+        (idiv(eax, ecx, edx), [0x0E, 0x80, 0x32, 0x8A]),
+        (mflou(eax), [0x1E, 0x80, 0x00, 0x80]),
+        (mfloi(edx), [0x1F, 0x90, 0x00, 0x80]),
+    ];
+
+    for (instr, word) in cases {
+        let bytes = instr.encode().unwrap();
+        assert_eq!(bytes[..2], [0x62, 0x80]);
+        assert_eq!(bytes[2..], word, "{:?}", instr);
+    }
+}
+
+#[test]
+fn alu_decode_identity() {
+    use num::FromPrimitive;
+
+    let dp_cntls = [
+        DpCntl::Word,
+        DpCntl::Short,
+        DpCntl::LL,
+        DpCntl::HL,
+        DpCntl::LH,
+        DpCntl::HH,
+    ];
+    let operands = [
+        Operand::Reg(Register::EDX),
+        Operand::Const(Const::Number(1)),
+    ];
+
+    for sub_op in (0..64).filter_map(SubOpXalu::from_u8) {
+        for dp_cntl in dp_cntls {
+            for flags in [Flags::Keep, Flags::Update] {
+                for operand in operands {
+                    let instr = alu(
+                        sub_op,
+                        dp_cntl,
+                        flags,
+                        Register::EAX,
+                        Register::ECX,
+                        operand,
+                    );
+                    let (decoded, _) = Instruction::decode(&instr.encode().unwrap()).unwrap();
+                    assert_eq!(decoded.opcode, instr.opcode);
+                    assert_eq!(decoded.leftovers, 0);
+                    assert_eq!(
+                        format!("{:?}", decoded.function),
+                        format!("{:?}", instr.function)
+                    );
+                }
+            }
+        }
+    }
+
+    assert_eq!(
+        xor(Register::EAX, Register::EAX, Register::ECX).opcode,
+        Opcode::XALUR
+    );
+    assert_eq!(ctc2(Cp2Reg::EFLAGS, Register::EAX).opcode, Opcode::XALU);
+}
+
+#[test]
+fn xio_encoding_follows_the_feature() {
+    let instr = iow8(Register::EDX, Register::EAX);
+    assert_eq!(instr.encode().is_ok(), cfg!(feature = "unconfirmed"));
 }
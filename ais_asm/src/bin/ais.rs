@@ -0,0 +1,309 @@
+// Command line front end for the assembler, disassembler and the encoder.
+
+use ais_asm::ais::{AisError, Instruction};
+use ais_asm::disasm;
+use ais_asm::dynasm::{DynAsm, DynAsmError};
+use ais_asm::encode::encode32;
+use ais_asm::image;
+use ais_asm::parse::{self, ParseError};
+
+use std::fs;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage:
+  ais asm <source> [-o <file>] [--format bin|elf|hex] [--base <addr>] [--header pic|abs|none]
+  ais disasm <file> [--base <addr>] [--header pic|abs|none]
+  ais decode <word>
+  ais encode <instruction> | <field=value>...
+
+--base     load address, default 0x480000
+--header   x86 code that switches to AIS mode, followed by the code and a ret:
+           pic   position independent call, pop, add, jmpai (default)
+           abs   mov eax to the absolute address, jmpai
+           none  only the AIS code, without ret";
+
+// Fields are only read through the Debug print
+#[allow(dead_code)]
+#[derive(Debug)]
+enum TopError {
+    Usage(String),
+    IoError(std::io::Error),
+    AisError(AisError),
+    DynAsmError(DynAsmError),
+    ParseError(ParseError),
+    SourceError(usize, ParseError), // Parse error at a line of the source
+    BadElf,
+}
+
+impl From<std::io::Error> for TopError {
+    fn from(x: std::io::Error) -> Self {
+        Self::IoError(x)
+    }
+}
+
+impl From<AisError> for TopError {
+    fn from(x: AisError) -> Self {
+        Self::AisError(x)
+    }
+}
+
+impl From<DynAsmError> for TopError {
+    fn from(x: DynAsmError) -> Self {
+        Self::DynAsmError(x)
+    }
+}
+
+impl From<ParseError> for TopError {
+    fn from(x: ParseError) -> Self {
+        Self::ParseError(x)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Header {
+    Pic,
+    Abs,
+    None,
+}
+
+struct Options {
+    args: Vec<String>, // Positional arguments
+    output: Option<String>,
+    format: String,
+    base: u32,
+    header: Header,
+}
+
+fn usage(text: &str) -> TopError {
+    TopError::Usage(text.to_string())
+}
+
+fn options(args: &[String]) -> Result<Options, TopError> {
+    let mut options = Options {
+        args: Vec::new(),
+        output: None,
+        format: "bin".to_string(),
+        base: 0x48_0000,
+        header: Header::Pic,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(usage(&format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "-o" => options.output = Some(value()?.clone()),
+            "--format" => options.format = value()?.clone(),
+            "--base" => {
+                let base = parse::number(value()?)?;
+                options.base = base.try_into().map_err(|_| usage("base out of range"))?;
+            }
+            "--header" => {
+                options.header = match value()?.as_str() {
+                    "pic" => Header::Pic,
+                    "abs" => Header::Abs,
+                    "none" => Header::None,
+                    x => return Err(usage(&format!("unknown header {}", x))),
+                }
+            }
+            _ => options.args.push(arg.clone()),
+        }
+    }
+
+    Ok(options)
+}
+
+fn gen_header(asm: &mut DynAsm, header: Header) -> Result<(), DynAsmError> {
+    match header {
+        Header::Pic => asm.gen_header(),
+        Header::Abs => {
+            let start = asm.new_sym();
+            asm.gen_x86_jmpai_symbol(start)?;
+            asm.set_sym_here(start)?;
+        }
+        Header::None => {}
+    }
+    Ok(())
+}
+
+// Bytes of the header on its own, as they appear at the start of the output
+fn header_bytes(base: u32, header: Header) -> Result<Vec<u8>, DynAsmError> {
+    let mut asm = DynAsm::new(base);
+    gen_header(&mut asm, header)?;
+    Ok(asm.memory().clone())
+}
+
+fn assemble(options: &Options) -> Result<(), TopError> {
+    let [source] = &options.args[..] else {
+        return Err(usage("asm takes one source file"));
+    };
+    let text = fs::read_to_string(source)?;
+
+    let mut asm = DynAsm::new(options.base);
+    gen_header(&mut asm, options.header)?;
+    parse::assemble(&mut asm, &text).map_err(|(line, e)| TopError::SourceError(line, e))?;
+    if options.header != Header::None {
+        asm.gen_footer();
+    }
+
+    for warning in asm.warnings() {
+        let text = disasm::disasm(&warning.instr);
+        eprintln!("warning: {}: {:?}", text, warning.reason);
+    }
+
+    let code = asm.memory().clone();
+    let len = code.len();
+    let (data, extension) = match options.format.as_str() {
+        "bin" => (code, "bin"),
+        "elf" => (image::elf32(options.base, &code), "elf"),
+        "hex" => (image::intel_hex(options.base, &code).into_bytes(), "hex"),
+        x => return Err(usage(&format!("unknown format {}", x))),
+    };
+
+    let output = options
+        .output
+        .clone()
+        .unwrap_or(format!("out.{}", extension));
+    fs::write(&output, data)?;
+    println!("{}: {} bytes at {:#X}", output, len, options.base);
+    Ok(())
+}
+
+// Code and load address from the first program header of an ELF file, or the raw file
+fn load(data: &[u8], base: u32) -> Result<(Vec<u8>, u32), TopError> {
+    if !data.starts_with(b"\x7FELF") {
+        return Ok((data.to_vec(), base));
+    }
+
+    let u32_at = |x: usize| {
+        let bytes = data.get(x..x.checked_add(4)?)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let segment = || {
+        let phdr = u32_at(28)? as usize;
+        let offset = u32_at(phdr.checked_add(4)?)? as usize;
+        let vaddr = u32_at(phdr.checked_add(8)?)?;
+        let size = u32_at(phdr.checked_add(16)?)? as usize;
+        let code = data.get(offset..offset.checked_add(size)?)?;
+        Some((code.to_vec(), vaddr))
+    };
+    segment().ok_or(TopError::BadElf)
+}
+
+fn disassemble(options: &Options) -> Result<(), TopError> {
+    let [file] = &options.args[..] else {
+        return Err(usage("disasm takes one file"));
+    };
+    let (code, base) = load(&fs::read(file)?, options.base)?;
+
+    let header = header_bytes(base, options.header)?;
+    if !code.starts_with(&header) {
+        return Err(usage(&format!("no {:?} header", options.header)));
+    }
+    if !header.is_empty() {
+        println!("{:08X}  ; header, {} bytes", base, header.len());
+    }
+
+    let start = base.wrapping_add(header.len() as u32);
+    for line in disasm::listing(start, &code[header.len()..]) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn decode(options: &Options) -> Result<(), TopError> {
+    let [word] = &options.args[..] else {
+        return Err(usage("decode takes one word"));
+    };
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix("0X"))
+        .unwrap_or(word);
+    let word = u32::from_str_radix(digits, 16).map_err(|_| usage("word is not hex"))?;
+
+    let mut bytes = vec![0x62, 0x80];
+    bytes.extend_from_slice(&word.to_le_bytes());
+    let (instr, _) = Instruction::decode(&bytes)?;
+
+    println!("{}", disasm::disasm(&instr));
+    for (name, value) in disasm::fields(&instr) {
+        println!("  {:<10} {}", name, value);
+    }
+    Ok(())
+}
+
+fn encode(options: &Options) -> Result<(), TopError> {
+    let text = options.args.join(" ");
+    let instr = match text.contains('=') {
+        true => {
+            let pairs: Vec<(&str, &str)> = text
+                .split_whitespace()
+                .map(|x| x.split_once('=').ok_or(usage(&format!("bad field {}", x))))
+                .collect::<Result<_, _>>()?;
+            parse::fields(&pairs)?
+        }
+        false => parse::instruction(&text)?,
+    };
+
+    let word = encode32(&instr)?;
+    let bytes: Vec<String> = instr
+        .encode()?
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    println!(
+        "{:#010X}  {}  {}",
+        word,
+        bytes.join(" "),
+        disasm::disasm(&instr)
+    );
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), TopError> {
+    let Some((command, rest)) = args.split_first() else {
+        return Err(usage("missing command"));
+    };
+    let options = options(rest)?;
+
+    match command.as_str() {
+        "asm" => assemble(&options),
+        "disasm" => disassemble(&options),
+        "decode" => decode(&options),
+        "encode" => encode(&options),
+        x => Err(usage(&format!("unknown command {}", x))),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(TopError::Usage(text)) => {
+            eprintln!("{}\n\n{}", text, USAGE);
+            ExitCode::FAILURE
+        }
+        Err(TopError::SourceError(line, e)) => {
+            eprintln!("line {}: {:?}", line, e);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("{:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[test]
+fn load_checks_elf_bounds() {
+    let code = [0x62, 0x80, 0x47, 0x00, 0x10, 0x18, 0xC3];
+    let elf = image::elf32(0x48_0000, &code);
+    let (loaded, base) = load(&elf, 0).unwrap();
+    assert_eq!((&loaded[..], base), (&code[..], 0x48_0000));
+
+    // Inside the ELF header, the program header and the segment
+    for len in [4, 32, 0x54 + code.len() - 1] {
+        assert!(matches!(load(&elf[..len], 0), Err(TopError::BadElf)));
+    }
+    assert_eq!(load(&code, 0x1000).unwrap(), (code.to_vec(), 0x1000));
+}
@@ -1,154 +1,98 @@
-use crate::ais::{AisError, Field, Function, Instruction, Opcode, Register, SubOp};
-use crate::{bit, bits};
+use crate::ais::{
+    AisError, Field, Function, Instruction, Opcode, Register, XjCond, XJ_COND_ENABLE,
+    XJ_TTTN_ALWAYS,
+};
+use crate::spec::{self, FieldSpec, Kind};
 use num::FromPrimitive;
 
-fn decode_xalu_function(word: u32) -> Result<Function, AisError> {
-    let sub_op_bits = bits(word, 4, 0);
-    let dp_cntl_bits = bits(word, 7, 5);
-    let sub_op = FromPrimitive::from_u32(sub_op_bits).ok_or(AisError::Decode(Field::Function))?;
-    let dp_cntl = FromPrimitive::from_u32(dp_cntl_bits).ok_or(AisError::Decode(Field::Function))?;
-    Ok(Function::Xalu(sub_op, dp_cntl))
+fn enumerated<T: FromPrimitive>(value: u32) -> Result<T, AisError> {
+    T::from_u32(value).ok_or(AisError::Decode(Field::Function))
 }
 
-fn decode_xio_function(word: u32) -> Result<Function, AisError> {
-    let sub_op_bits = bits(word, 10, 9);
-    let addr_size_bits = bit(word, 8) << 1 | bit(word, 0);
-    let size_bits = bits(word, 7, 6) << 1 | bit(word, 1);
-    let sel_bits = bits(word, 5, 2);
-
-    let sub_op = FromPrimitive::from_u32(sub_op_bits).ok_or(AisError::Decode(Field::Function))?;
-    let addr_size =
-        FromPrimitive::from_u32(addr_size_bits).ok_or(AisError::Decode(Field::Function))?;
-    let size = FromPrimitive::from_u32(size_bits).ok_or(AisError::Decode(Field::Function))?;
-    let sel = FromPrimitive::from_u32(sel_bits).ok_or(AisError::Decode(Field::Function))?;
-
-    Ok(Function::Xio(sub_op, addr_size, size, sel))
-}
-
-fn decode_xj_function(word: u32) -> Result<Function, AisError> {
-    let size =
-        FromPrimitive::from_u32(bits(word, 7, 6)).ok_or(AisError::Decode(Field::Function))?;
-    let mode =
-        FromPrimitive::from_u32(bits(word, 1, 0)).ok_or(AisError::Decode(Field::Function))?;
-    Ok(Function::Xj(size, mode))
+// Fields with TryFrom<u8> tables instead of plain discriminants
+fn table<T: TryFrom<u8>>(value: u32, field: Field) -> Result<T, AisError> {
+    u8::try_from(value)
+        .ok()
+        .and_then(|x| T::try_from(x).ok())
+        .ok_or(AisError::Decode(field))
 }
 
-fn decode_xmisc_function(word: u32) -> Result<Function, AisError> {
-    let subfunc =
-        FromPrimitive::from_u32(bits(word, 10, 6)).ok_or(AisError::Decode(Field::Function))?;
-    let other_bits = bits(word, 5, 0).try_into().unwrap();
-    Ok(Function::Xmisc(subfunc, other_bits))
-}
-
-fn decode_function(opcode: Opcode, word: u32) -> Result<Function, AisError> {
-    match opcode {
-        Opcode::XIOR | Opcode::XIOW => decode_xio_function(word),
-        Opcode::XLEAD | Opcode::XLEAI => {
-            let addr_size_bits = bit(word, 8) << 1 | bit(word, 0);
-            let size_bits = bit(word, 2) << 3 | bits(word, 7, 6) << 1 | bit(word, 1);
+// Function from the raw values of its fields, the kind of the first field picks the variant
+pub fn function(fields: &[FieldSpec], values: &[u32]) -> Result<Function, AisError> {
+    let f = Field::Function;
 
-            let addr_size =
-                FromPrimitive::from_u32(addr_size_bits).ok_or(AisError::Decode(Field::Function))?;
-            let size =
-                FromPrimitive::from_u32(size_bits).ok_or(AisError::Decode(Field::Function))?;
-
-            Ok(Function::Xlea(addr_size, size))
+    Ok(match (fields.first().map(|x| x.kind), values) {
+        (Some(Kind::SubOpXalu), &[sub_op, dp_cntl]) => {
+            Function::Xalu(enumerated(sub_op)?, enumerated(dp_cntl)?)
         }
-        Opcode::XPUSH | Opcode::XPOP | Opcode::XPUSHIP => {
-            let sub_op_bits = bits(word, 10, 9);
-            let addr_size_bits = bit(word, 8) << 1 | bit(word, 0);
-            let size_bits = bits(word, 7, 6) << 1 | bit(word, 1);
-            let sel_bits = bits(word, 5, 2);
-
-            let addr_size =
-                FromPrimitive::from_u32(addr_size_bits).ok_or(AisError::Decode(Field::Function))?;
-            let size =
-                FromPrimitive::from_u32(size_bits).ok_or(AisError::Decode(Field::Function))?;
-            let sel = FromPrimitive::from_u32(sel_bits).ok_or(AisError::Decode(Field::Function))?;
-
-            Ok(Function::Xls(
-                SubOp::Raw(sub_op_bits.try_into().unwrap()),
-                addr_size,
-                size,
-                sel,
-            ))
+        (Some(Kind::SubOpXio), &[sub_op, addr_size, size, sel]) => Function::Xio(
+            table(sub_op, f)?,
+            enumerated(addr_size)?,
+            enumerated(size)?,
+            enumerated(sel)?,
+        ),
+        (Some(Kind::SubOp), &[sub_op, addr_size, size, sel]) => Function::Xls(
+            table(sub_op, f)?,
+            enumerated(addr_size)?,
+            enumerated(size)?,
+            enumerated(sel)?,
+        ),
+        (Some(Kind::XjSize), &[size, cond, mode]) => {
+            let cond = match (cond >> 4, cond & 0xF) {
+                (0, XJ_TTTN_ALWAYS) => XjCond::Always,
+                (XJ_COND_ENABLE, tttn) => XjCond::If(enumerated(tttn)?),
+                _ => XjCond::Raw(cond as u16),
+            };
+            Function::Xj(enumerated(size)?, cond, enumerated(mode)?)
         }
-        _ => todo!(),
-    }
-}
-
-fn decode_opcode(word: u32) -> Result<Opcode, AisError> {
-    let opcode_bits = (word >> 26) & 0x3F;
-    FromPrimitive::from_u32(opcode_bits).ok_or(AisError::Decode(Field::Opcode))
-}
-
-fn reg(bits: u32, field: Field) -> Result<Register, AisError> {
-    let x: u8 = bits.try_into().map_err(|_| AisError::Decode(field))?;
-    x.try_into().map_err(|_| AisError::Decode(field))
+        (Some(Kind::AddrSize), &[addr_size, size]) => {
+            Function::Xlea(enumerated(addr_size)?, enumerated(size)?)
+        }
+        (Some(Kind::SubFunc), &[sub_func, other]) => {
+            let other = other.try_into().map_err(|_| AisError::Decode(f))?;
+            Function::Xmisc(enumerated(sub_func)?, other)
+        }
+        _ => return Err(AisError::Decode(f)),
+    })
 }
 
-fn rs(word: u32) -> Result<Option<Register>, AisError> {
-    let bits = bits(word, 25, 21);
-    reg(bits, Field::RS).map(Some)
-}
+fn operand(instr: &mut Instruction, field: &FieldSpec, value: u32) -> Result<(), AisError> {
+    let reg = |value| table::<Register>(value, field.field).map(Some);
+
+    match field.field {
+        Field::RS => instr.rs = reg(value)?,
+        Field::RT => instr.rt = reg(value)?,
+        Field::RD => instr.rd = reg(value)?,
+        Field::Immediate => instr.imm = Some(value.try_into().unwrap()),
+        Field::Const => instr.constant = Some(table(value, field.field)?),
+        Field::Offset => instr.offset = Some(table(value, field.field)?),
+        Field::Opcode | Field::Function => unreachable!(),
+    }
 
-fn rt(word: u32) -> Result<Option<Register>, AisError> {
-    let bits = bits(word, 20, 16);
-    reg(bits, Field::RT).map(Some)
+    Ok(())
 }
 
-fn rd(word: u32) -> Result<Option<Register>, AisError> {
-    let bits = bits(word, 15, 11);
-    reg(bits, Field::RD).map(Some)
+fn decode_opcode(word: u32) -> Result<Opcode, AisError> {
+    let opcode_bits = spec::extract(word, spec::OPCODE);
+    FromPrimitive::from_u32(opcode_bits).ok_or(AisError::Decode(Field::Opcode))
 }
 
 pub fn decode32(word: u32) -> Result<Instruction, AisError> {
     let opcode = decode_opcode(word)?;
+    let format = spec::format(opcode).ok_or(AisError::Decode(Field::Opcode))?;
     let mut instr = Instruction::new(opcode);
 
-    if instr.is_i_type() {
-        instr.rs = rs(word)?;
-        instr.rt = rt(word)?;
-        let imm_bits = bits(word, 15, 0).try_into().unwrap();
-        instr.imm = Some(imm_bits);
-    } else if instr.is_xalu_type() {
-        instr.function = Some(decode_xalu_function(word)?);
-        instr.rs = rs(word)?;
-        instr.rt = rt(word)?;
-        instr.rd = rd(word)?;
-    } else if instr.is_xalui_type() {
-        instr.function = Some(decode_xalu_function(word)?);
-        instr.rs = rs(word)?;
-        instr.rd = rd(word)?;
-        instr.constant = bits(word, 20, 16)
-            .try_into()
-            .ok()
-            .and_then(|x: u8| x.try_into().ok())
-            .ok_or(AisError::Decode(Field::Const))
-            .map(Some)?;
-    } else if instr.opcode == Opcode::XJ {
-        instr.rt = rt(word)?;
-        instr.function = Some(decode_xj_function(word)?);
-    } else if instr.opcode == Opcode::XMISC {
-        instr.rs = rs(word)?;
-        instr.rt = rt(word)?;
-        instr.rd = rd(word)?;
-        instr.function = Some(decode_xmisc_function(word)?);
-    } else if instr.is_xls_type() || instr.opcode == Opcode::XLEAD {
-        // The XLS type has an other order of fields
-        let xls_rs_bits = bits(word, 15, 11);
-
-        instr.rs = Some(reg(xls_rs_bits, Field::RS)?);
-        instr.rt = rt(word)?;
-        instr.offset = bits(word, 25, 21)
-            .try_into()
-            .ok()
-            .and_then(|x: u8| x.try_into().ok())
-            .ok_or(AisError::Decode(Field::Offset))
-            .map(Some)?;
-        instr.function = Some(decode_function(instr.opcode, word)?);
-    } else {
-        return Err(AisError::Decode(Field::Opcode));
+    for field in format.operands {
+        operand(&mut instr, field, spec::extract(word, field.bits))?;
+    }
+
+    if !format.function.is_empty() {
+        let values: Vec<u32> = format
+            .function
+            .iter()
+            .map(|field| spec::extract(word, field.bits))
+            .collect();
+        instr.function = Some(function(format.function, &values)?);
     }
 
     Ok(instr)
@@ -173,3 +117,63 @@ pub fn decode(bytes: &[u8]) -> Result<(Instruction, usize), AisError> {
 
     Ok((instr, 6))
 }
+
+#[test]
+fn document_vectors() {
+    use crate::disasm::{disasm, fields};
+
+    let lines: Vec<&str> = include_str!("../conformance.txt")
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .collect();
+    assert!(!lines.is_empty());
+
+    for line in lines {
+        let columns: Vec<&str> = line.split('|').map(str::trim).collect();
+        let [name, bytes, mnemonic, expected] = columns[..] else {
+            panic!("malformed vector: {}", line);
+        };
+        let bytes: Vec<u8> = bytes
+            .split_whitespace()
+            .map(|x| u8::from_str_radix(x, 16).unwrap())
+            .collect();
+
+        let (instr, len) = decode(&bytes).unwrap();
+        assert_eq!(len, bytes.len(), "{}", name);
+        assert_eq!(instr.leftovers, 0, "{}", name);
+        assert_eq!(disasm(&instr), mnemonic, "{}", name);
+
+        let decoded: Vec<String> = fields(&instr)
+            .into_iter()
+            .map(|(field, value)| format!("{}={}", field, value))
+            .collect();
+        assert_eq!(decoded.join(" "), expected, "{}", name);
+
+        assert_eq!(crate::encode::encode(&instr).unwrap(), bytes, "{}", name);
+    }
+}
+
+#[test]
+fn xj_condition_is_lossless() {
+    use crate::ais::{XjMode, XjSize};
+    use crate::disasm::disasm;
+
+    // Condition bits that are neither always nor an enabled tttn still decode and encode back
+    for word in [0x1800_0000, 0x1800_0008, 0x1800_4004, 0x1800_F83C] {
+        let instr = decode32(word).unwrap();
+        assert!(matches!(
+            instr.function,
+            Some(Function::Xj(_, XjCond::Raw(_), _))
+        ));
+        assert_eq!(crate::encode::encode32(&instr).unwrap(), word);
+    }
+
+    let instr = decode32(0x1800_0000).unwrap();
+    assert!(matches!(
+        instr.function,
+        Some(Function::Xj(XjSize::Bits16, XjCond::Raw(0), XjMode::AIS))
+    ));
+    assert_eq!(disasm(&instr), "xj.b16.ais r0");
+    let parsed = crate::parse::instruction("xj.b16.ais r0").unwrap();
+    assert_eq!(crate::encode::encode32(&parsed).unwrap(), 0x1800_0000);
+}
@@ -0,0 +1,266 @@
+// Text form of instructions, driven by spec.
+//
+// The mnemonic is followed by the function fields as dot separated suffixes, in the order of the
+// Function variant, then the operands in assembly order:
+//   xalur.add.word eax, ecx, edx
+//   xl.0.a32.b32.ds eax, ebx, os
+// Function values without a name are written as numbers, constant and offset encodings without a
+// known value as raw(n).
+
+use crate::ais::{Const, Field, Function, Instruction, Offset, Register};
+use crate::encode::function_values;
+use crate::spec::{self, FieldSpec, Kind};
+
+pub fn register(reg: Register) -> String {
+    match spec::name(Kind::Register, reg.0.into()) {
+        Some(name) => name.to_string(),
+        None => format!("r{}", reg.0),
+    }
+}
+
+pub fn constant(c: Const) -> String {
+    match c {
+        Const::Number(x) => x.to_string(),
+        Const::Raw(x) => format!("raw({})", x),
+        named => format!("{:?}", named).to_lowercase(),
+    }
+}
+
+pub fn offset(offset: Offset) -> String {
+    match offset {
+        Offset::Number(x) => x.to_string(),
+        Offset::Raw(x) => format!("raw({})", x),
+        named => format!("{:?}", named).to_lowercase(),
+    }
+}
+
+// Function values by field name, as names or as numbers when they have none
+fn function_names(instr: &Instruction) -> Vec<(&'static str, String)> {
+    let (Some(format), Some(function)) = (instr.format(), instr.function) else {
+        return Vec::new();
+    };
+
+    let values = match function {
+        Function::Raw(x) => return vec![("function", format!("raw({})", x))],
+        function => function_values(function).unwrap_or_default(),
+    };
+
+    values
+        .into_iter()
+        .zip(format.function)
+        .map(|(value, field)| match spec::name(field.kind, value) {
+            Some(name) => (field.name, name.to_string()),
+            None => (field.name, value.to_string()),
+        })
+        .collect()
+}
+
+fn operand(instr: &Instruction, field: &FieldSpec) -> String {
+    let operand = match field.field {
+        Field::RS => instr.rs.map(register),
+        Field::RT => instr.rt.map(register),
+        Field::RD => instr.rd.map(register),
+        Field::Immediate => instr.imm.map(|x| format!("0x{:X}", x)),
+        Field::Const => instr.constant.map(constant),
+        Field::Offset => instr.offset.map(offset),
+        Field::Opcode | Field::Function => None,
+    };
+    operand.unwrap_or_else(|| "?".to_string())
+}
+
+pub fn disasm(instr: &Instruction) -> String {
+    let spec = spec::opcode(instr.opcode);
+    let mut text = spec.mnemonic.to_string();
+    for (field, name) in function_names(instr) {
+        // An XJ without any condition bits is written without the condition
+        if field == "cond" && name == "0" {
+            continue;
+        }
+        text = format!("{}.{}", text, name);
+    }
+
+    let Some(format) = spec.format else {
+        return text + " ; layout unknown";
+    };
+
+    let operands: Vec<String> = format
+        .operands
+        .iter()
+        .map(|field| operand(instr, field))
+        .collect();
+
+    if !operands.is_empty() {
+        text = format!("{} {}", text, operands.join(", "));
+    }
+    if instr.leftovers != 0 {
+        text = format!("{} ; leftovers 0x{:X}", text, instr.leftovers);
+    }
+    text
+}
+
+// Every field of the format by its spec name, starting with the opcode
+pub fn fields(instr: &Instruction) -> Vec<(&'static str, String)> {
+    let spec = spec::opcode(instr.opcode);
+    let mut fields = vec![("opcode", spec.mnemonic.to_string())];
+
+    if let Some(format) = spec.format {
+        for field in format.operands {
+            fields.push((field.name, operand(instr, field)));
+        }
+        fields.extend(function_names(instr));
+    }
+
+    if instr.leftovers != 0 {
+        fields.push(("leftovers", format!("0x{:X}", instr.leftovers)));
+    }
+    fields
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    bytes.join(" ")
+}
+
+// One line per instruction with address and bytes. Bytes that don't decode are listed as .byte
+// until the next instruction, at most 6 per line.
+pub fn listing(base: u32, bytes: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let addr = base.wrapping_add(offset as u32);
+        let (len, text) = match Instruction::decode(&bytes[offset..]) {
+            Ok((instr, len)) => (len, disasm(&instr)),
+            Err(_) => {
+                let len = (offset + 1..bytes.len())
+                    .take(5)
+                    .find(|&x| Instruction::decode(&bytes[x..]).is_ok())
+                    .map_or((bytes.len() - offset).min(6), |x| x - offset);
+                (len, format!(".byte {}", hex(&bytes[offset..offset + len])))
+            }
+        };
+
+        let code = hex(&bytes[offset..offset + len]);
+        lines.push(format!("{:08X}  {:<17}  {}", addr, code, text));
+        offset += len;
+    }
+
+    lines
+}
+
+#[test]
+fn builders_disassemble() {
+    use crate::ais::{AddrSize, Cond, Cp2Reg, Sel, Size, XjCond, XjMode, XjSize};
+    use crate::asm;
+
+    let (eax, ecx, edx) = (Register::EAX, Register::ECX, Register::EDX);
+    let cases = [
+        (asm::xori(eax, ecx, 0x1234), "ori eax, ecx, 0x1234"),
+        (asm::add(eax, ecx, edx), "xalur.add.word eax, ecx, edx"),
+        (
+            asm::addi_nf(eax, ecx, Const::Number(5)),
+            "xalui.add.word eax, ecx, 5",
+        ),
+        (asm::cfc2(eax, Cp2Reg::EFLAGS), "xmisc.cfc2.0 r0, eax, r31"),
+        (
+            asm::pushsp(Size::Bits32, eax),
+            "xpush.0.a32.b32.ss eax, esp, -4",
+        ),
+        (
+            asm::load(
+                Size::Bits8H,
+                eax,
+                ecx,
+                Offset::Raw(13),
+                Sel::GDT,
+                AddrSize::Bits16,
+            ),
+            "xl.0.a16.b8h.gdt eax, ecx, raw(13)",
+        ),
+        (asm::ior8(edx, eax), "xior.norm.a16.b8l.flat eax, edx, 0"),
+        (asm::jcc(Cond::NZ, eax), "xj.b32.nz.ais eax"),
+        (asm::jx86(eax), "xj.b32.always.x86 eax"),
+        (
+            asm::xj(XjSize::Bits32, XjCond::Raw(0x30), XjMode::AIS, eax),
+            "xj.b32.48.ais eax",
+        ),
+        (
+            asm::lead(eax, ecx, Offset::DFOS, AddrSize::Bits32, Size::SAS),
+            "xlead.a32.sas eax, ecx, dfos",
+        ),
+        (
+            asm::leai(eax, ecx, edx, AddrSize::Bits32, Size::Bits32),
+            "xleai ; layout unknown",
+        ),
+    ];
+
+    for (instr, text) in cases {
+        assert_eq!(disasm(&instr), text);
+    }
+
+    // Decoded instructions disassemble the same as the builders
+    let (instr, _) = Instruction::decode(&asm::add(eax, ecx, edx).encode().unwrap()).unwrap();
+    assert_eq!(disasm(&instr), "xalur.add.word eax, ecx, edx");
+}
+
+#[test]
+fn builders_match_formats() {
+    use crate::ais::SubOpXalu;
+    use crate::asm;
+
+    // Every builder sets exactly the operand fields its format encodes
+    let (eax, ecx) = (Register::EAX, Register::ECX);
+    let instrs = [
+        asm::xori(eax, ecx, 1),
+        asm::xaddi(eax, ecx, 1),
+        asm::sub(eax, ecx, eax),
+        asm::alu(
+            SubOpXalu::SHL,
+            crate::ais::DpCntl::Word,
+            asm::Flags::Keep,
+            eax,
+            ecx,
+            Const::Number(1),
+        ),
+        asm::cfc2(ecx, crate::ais::Cp2Reg::EFLAGS),
+        asm::popsp(crate::ais::Size::Bits32, eax),
+        asm::iow32(ecx, eax),
+        asm::j(eax),
+        asm::ldesc(Register::DS, eax, Offset::Number(0), crate::ais::Sel::GDT),
+    ];
+
+    for instr in instrs {
+        let format = instr.format().unwrap();
+        let has = |field| format.operands.iter().any(|x| x.field == field);
+        assert_eq!(instr.rs.is_some(), has(Field::RS), "{:?}", instr);
+        assert_eq!(instr.rt.is_some(), has(Field::RT), "{:?}", instr);
+        assert_eq!(instr.rd.is_some(), has(Field::RD), "{:?}", instr);
+        assert_eq!(instr.imm.is_some(), has(Field::Immediate), "{:?}", instr);
+        assert_eq!(instr.constant.is_some(), has(Field::Const), "{:?}", instr);
+        assert_eq!(instr.offset.is_some(), has(Field::Offset), "{:?}", instr);
+        assert_eq!(
+            instr.function.is_some(),
+            !format.function.is_empty(),
+            "{:?}",
+            instr
+        );
+    }
+}
+
+#[test]
+fn listing_marks_x86() {
+    use crate::asm;
+
+    let mut bytes = vec![0x90];
+    bytes.extend(asm::jx86(Register::EAX).encode().unwrap());
+    bytes.push(0xC3);
+
+    assert_eq!(
+        listing(0x48_0000, &bytes),
+        [
+            "00480000  90                 .byte 90",
+            "00480001  62 80 47 00 10 18  xj.b32.always.x86 eax",
+            "00480007  C3                 .byte C3",
+        ]
+    );
+}
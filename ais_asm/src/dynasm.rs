@@ -1,12 +1,34 @@
-use crate::ais::{AisError, Const, Instruction, Register, Size};
+use crate::ais::{
+    AddrSize, AisError, Cond, Const, Cp2Reg, Instruction, Offset, Register, Sel, Size,
+};
 use crate::asm;
+use crate::layout::{Item, Layout, X86Ranges};
+use crate::macros::Macro;
+use crate::peephole::{self, Peephole, PeepholeStats};
+use crate::regalloc::RegAlloc;
+use crate::synth::Goal;
+use crate::translator::{self, Dependency, TranslatorState};
+use crate::validate::{Diagnostic, Reason, Severity, Validator};
+use crate::x86::{self, Width, X86Error};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub enum DynAsmError {
     AisError(AisError),
+    X86Error(X86Error),
     InvalidSym,
     SymbolRedefined,
-    ResolveUnstable,
+    OutOfRegisters,
+    RegisterUnavailable(Register),
+    RegisterNotAllocated(Register),
+    SpillOrder(Register),
+    ArgumentCount,
+    UnknownMacro,
+    MacroArgument,
+    NotSegmentRegister(Register),
+    Invalid(Reason), // Rejected in strict mode
 }
 
 impl From<AisError> for DynAsmError {
@@ -15,38 +37,52 @@ impl From<AisError> for DynAsmError {
     }
 }
 
-#[derive(Copy, Clone)]
-pub struct Sym(usize);
-
-enum Symbol {
-    Unresolved(Vec<SymRef>),
-    Resolved(u32),
+impl From<X86Error> for DynAsmError {
+    fn from(x: X86Error) -> Self {
+        Self::X86Error(x)
+    }
 }
 
-#[derive(Debug)]
-enum SymRefKind {
-    HighImm,
-    LowImm,
+// Finding for an instruction passed to gen
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub instr: Instruction,
+    pub reason: Reason,
 }
 
-fn imm_high(addr: u32) -> u16 {
-    ((addr >> 16) & 0xFFFF).try_into().unwrap()
-}
+#[derive(Debug, Copy, Clone)]
+pub struct Sym(pub(crate) usize);
 
-fn imm_low(addr: u32) -> u16 {
-    (addr & 0xFFFF).try_into().unwrap()
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum SymRefKind {
+    HighImm,
+    LowImm,
+    Abs32, // x86 imm32 field
+    Rel32, // x86 rel32 field, relative to the end of the field
 }
 
-#[derive(Debug)]
-struct SymRef {
-    kind: SymRefKind,
-    offset: u32,
+// Encoded program with the peephole statistics of all its instructions
+struct Output {
+    memory: Vec<u8>,
+    symbols: Vec<Option<u32>>,
+    x86: X86Ranges,
+    stats: PeepholeStats,
 }
 
 pub struct DynAsm {
     base: u32,
-    memory: Vec<u8>,
-    symbols: Vec<Symbol>,
+    items: Vec<Item>,
+    pending: Vec<Item>,       // Instructions since the last label or x86 code
+    defined: Vec<bool>,       // Symbols that have a label
+    output: OnceCell<Output>, // Cleared when anything is added
+    regs: RegAlloc,
+    macros: HashMap<String, Rc<dyn Macro>>,
+    peephole: Peephole,
+    stats: PeepholeStats,
+    translator: Vec<Dependency>, // Translator state set up before the code
+    validator: Validator,
+    strict: bool,
+    warnings: Vec<Warning>,
 }
 
 const HEADER: &[u8] = &[
@@ -66,122 +102,192 @@ impl DynAsm {
     pub fn new(base: u32) -> Self {
         Self {
             base,
-            memory: Vec::new(),
-            symbols: Vec::new(),
+            items: Vec::new(),
+            pending: Vec::new(),
+            defined: Vec::new(),
+            output: OnceCell::new(),
+            regs: RegAlloc::new(),
+            macros: HashMap::new(),
+            peephole: Peephole::default(),
+            stats: PeepholeStats::default(),
+            translator: Vec::new(),
+            validator: Validator::new(),
+            strict: false,
+            warnings: Vec::new(),
         }
     }
 
-    fn offset(&self) -> u32 {
-        self.memory.len().try_into().unwrap()
+    fn check_sym(&self, sym: Sym) -> Result<(), DynAsmError> {
+        match sym.0 < self.defined.len() {
+            true => Ok(()),
+            false => Err(DynAsmError::InvalidSym),
+        }
     }
 
-    fn addr(&self) -> u32 {
-        self.offset() + self.base
-    }
+    // Run the peephole passes over the pending instructions
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
 
-    fn symbol(&mut self, sym: Sym) -> Result<&mut Symbol, DynAsmError> {
-        self.symbols.get_mut(sym.0).ok_or(DynAsmError::InvalidSym)
+        let block = core::mem::take(&mut self.pending);
+        let block = peephole::optimize(&self.peephole, &mut self.stats, block);
+        self.items.extend(block);
     }
 
-    fn sym_ref_resolve(&mut self, sym_ref: SymRef, addr: u32) -> Result<(), DynAsmError> {
-        println!("fixup: {:?} = {:X}", sym_ref, addr);
+    fn push_item(&mut self, item: Item) {
+        self.flush();
+        self.output.take();
+        self.items.push(item);
+    }
 
-        // Decode
-        let start = sym_ref.offset.try_into().unwrap();
-        let end = self.memory.len();
-        let bytes = self.memory.get_mut(start..end).unwrap();
-        let (mut instr, len) = Instruction::decode(bytes)?;
+    fn push_pending(&mut self, item: Item) {
+        self.output.take();
+        self.pending.push(item);
+    }
 
-        // Fixup
-        match sym_ref.kind {
-            SymRefKind::LowImm => {
-                instr.imm = Some(imm_low(addr));
-            }
-            SymRefKind::HighImm => {
-                instr.imm = Some(imm_high(addr));
+    // Lay out the program and encode it, items were checked when they were generated. Pending
+    // instructions are optimized the same way as when they are flushed.
+    fn output(&self) -> &Output {
+        self.output.get_or_init(|| {
+            let mut stats = self.stats;
+            let pending = peephole::optimize(&self.peephole, &mut stats, self.pending.clone());
+            let items: Vec<Item> = self.items.iter().cloned().chain(pending).collect();
+            let layout = Layout::new(self.base, &items, self.defined.len());
+            let (memory, x86) = layout.encode(self.base, &items).unwrap();
+            let symbols = layout.symbols;
+            Output {
+                memory,
+                symbols,
+                x86,
+                stats,
             }
-        }
+        })
+    }
 
-        // Encode
-        let new_bytes = instr.encode()?;
-        if new_bytes.len() != len {
-            return Err(DynAsmError::ResolveUnstable);
+    pub fn set_peephole(&mut self, peephole: Peephole) {
+        self.output.take();
+        self.peephole = peephole;
+    }
+
+    pub fn peephole_stats(&self) -> &PeepholeStats {
+        &self.output().stats
+    }
+
+    // Code before this point sets up dep, e.g. with an x86 cld before entering AIS mode
+    pub fn set_translator_state(&mut self, dep: Dependency) {
+        if !self.translator.contains(&dep) {
+            self.translator.push(dep);
         }
+        self.validator.establish(dep);
+    }
 
-        let old_bytes = &mut bytes[0..len];
-        old_bytes.copy_from_slice(&new_bytes);
+    // Reject instructions with validation errors in gen, instead of only reporting them as warnings
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
 
-        Ok(())
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
     }
 
-    fn sym_resolve(&mut self, sym: Sym, addr: u32) -> Result<(), DynAsmError> {
-        let symbol = self.symbol(sym)?;
+    pub fn new_sym(&mut self) -> Sym {
+        self.defined.push(false);
+        Sym(self.defined.len() - 1)
+    }
 
-        let sym_refs = match symbol {
-            Symbol::Unresolved(refs) => {
-                let refs = core::mem::take(refs);
-                *symbol = Symbol::Resolved(addr);
-                refs
-            }
-            Symbol::Resolved(_) => return Err(DynAsmError::SymbolRedefined),
-        };
+    pub fn new_sym_here(&mut self) -> Sym {
+        let sym = self.new_sym();
+        self.set_sym_here(sym).unwrap();
+        sym
+    }
+
+    // Address in the current layout. It can still move while later code resolves forward references.
+    // Symbols created after the last layout are not defined yet.
+    pub fn sym_addr(&self, sym: Sym) -> Result<Option<u32>, DynAsmError> {
+        self.check_sym(sym)?;
+        Ok(self.output().symbols.get(sym.0).copied().flatten())
+    }
 
-        for sym_ref in sym_refs {
-            self.sym_ref_resolve(sym_ref, addr)?;
+    pub fn set_sym_here(&mut self, sym: Sym) -> Result<(), DynAsmError> {
+        self.check_sym(sym)?;
+        if self.defined[sym.0] {
+            return Err(DynAsmError::SymbolRedefined);
         }
 
+        self.defined[sym.0] = true;
+        self.push_item(Item::Label(sym));
         Ok(())
     }
 
-    fn sym_ref(&mut self, sym: Sym, kind: SymRefKind) -> Result<u32, DynAsmError> {
-        let sym_ref = SymRef {
-            offset: self.offset(),
-            kind,
-        };
-
-        match self.symbol(sym)? {
-            Symbol::Unresolved(refs) => {
-                refs.push(sym_ref);
-                Ok(0)
-            }
-            Symbol::Resolved(addr) => Ok(*addr),
-        }
+    pub fn register_macro(&mut self, name: &str, mac: impl Macro + 'static) {
+        self.macros.insert(name.to_string(), Rc::new(mac));
     }
 
-    fn sym_ref_imm_high(&mut self, sym: Sym) -> Result<u16, DynAsmError> {
-        self.sym_ref(sym, SymRefKind::HighImm).map(imm_high)
+    pub(crate) fn find_macro(&self, name: &str) -> Result<Rc<dyn Macro>, DynAsmError> {
+        self.macros
+            .get(name)
+            .cloned()
+            .ok_or(DynAsmError::UnknownMacro)
     }
 
-    fn sym_ref_imm_low(&mut self, sym: Sym) -> Result<u16, DynAsmError> {
-        self.sym_ref(sym, SymRefKind::LowImm).map(imm_low)
+    pub fn regs(&mut self) -> &mut RegAlloc {
+        &mut self.regs
     }
 
-    pub fn new_sym(&mut self) -> Sym {
-        let entry = Symbol::Unresolved(Vec::new());
-        self.symbols.push(entry);
-        Sym(self.symbols.len() - 1)
+    // Allocate a temporary register, when none is free a reserved register is spilled to the stack.
+    // Spilled temporaries must be freed in reverse order and must not be live across jumps.
+    pub fn alloc_temp(&mut self) -> Result<Register, DynAsmError> {
+        if let Some(reg) = self.regs.alloc() {
+            return Ok(reg);
+        }
+
+        let reg = self.regs.spill().ok_or(DynAsmError::OutOfRegisters)?;
+        self.gen(asm::pushsp(Size::Bits32, reg))?;
+        Ok(reg)
     }
 
-    pub fn new_sym_here(&mut self) -> Sym {
-        let sym = self.new_sym();
-        self.sym_resolve(sym, self.addr()).unwrap();
-        sym
+    pub fn free_temp(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        if self.regs.free(reg)? {
+            self.gen(asm::popsp(Size::Bits32, reg))?;
+        }
+        Ok(())
     }
 
-    pub fn sym_addr(&mut self, sym: Sym) -> Result<Option<u32>, DynAsmError> {
-        Ok(match self.symbol(sym)? {
-            Symbol::Unresolved(_) => None,
-            Symbol::Resolved(addr) => Some(*addr),
-        })
+    // Scratch register for helpers, never spills because helpers emit jumps
+    pub(crate) fn scratch(&mut self) -> Result<Register, DynAsmError> {
+        self.regs.alloc().ok_or(DynAsmError::OutOfRegisters)
     }
 
-    pub fn set_sym_here(&mut self, sym: Sym) -> Result<(), DynAsmError> {
-        self.sym_resolve(sym, self.addr())
+    pub fn gen(&mut self, instruction: impl Into<Instruction>) -> Result<(), DynAsmError> {
+        let instruction = instruction.into();
+        instruction.encode()?;
+
+        let reasons = self.validator.check(&instruction);
+        let error = reasons.iter().find(|r| r.severity() == Severity::Error);
+        if let (true, Some(reason)) = (self.strict, error) {
+            return Err(DynAsmError::Invalid(*reason));
+        }
+        self.warnings
+            .extend(reasons.into_iter().map(|reason| Warning {
+                instr: instruction,
+                reason,
+            }));
+
+        self.push_pending(Item::Instr(instruction));
+        Ok(())
     }
 
-    pub fn gen(&mut self, instruction: Instruction) -> Result<(), DynAsmError> {
-        let instr = instruction.encode()?;
-        self.memory.extend_from_slice(instr.as_slice());
+    // Instruction with the high or low half of the sym address as immediate
+    fn gen_sym_imm(
+        &mut self,
+        instr: Instruction,
+        sym: Sym,
+        kind: SymRefKind,
+    ) -> Result<(), DynAsmError> {
+        self.check_sym(sym)?;
+        instr.encode()?;
+        self.push_pending(Item::SymImm(instr, sym, kind));
         Ok(())
     }
 
@@ -201,24 +307,62 @@ impl DynAsm {
         Ok(())
     }
 
-    pub fn gen_load_symbol(&mut self, dst: Register, sym: Sym) -> Result<(), DynAsmError> {
-        let low = self.sym_ref_imm_low(sym)?;
-        self.gen(asm::xori(dst, Register::R0, low))?;
+    pub fn gen_add_imm(&mut self, dst: Register, imm: u32) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load(tmp, imm)?;
+        self.gen(asm::add(dst, dst, tmp))?;
+        self.free_temp(tmp)
+    }
 
-        let high = self.sym_ref_imm_high(sym)?;
-        self.gen(asm::xoriu(dst, dst, high))
+    pub fn gen_sub_imm(&mut self, dst: Register, imm: u32) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load(tmp, imm)?;
+        self.gen(asm::sub(dst, dst, tmp))?;
+        self.free_temp(tmp)
     }
 
-    pub fn gen_jump(&mut self, sym: Sym) -> Result<(), DynAsmError> {
-        self.gen_load_symbol(Register::R4, sym)?;
-        self.gen(asm::j(Register::R4))?;
+    pub fn gen_load_symbol(&mut self, dst: Register, sym: Sym) -> Result<(), DynAsmError> {
+        self.check_sym(sym)?;
+        self.push_pending(Item::SymLoad(dst, sym));
         Ok(())
     }
 
+    pub fn gen_jump(&mut self, sym: Sym) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load_symbol(tmp, sym)?;
+        self.gen(asm::j(tmp))?;
+        self.free_temp(tmp)
+    }
+
+    // Jump to sym when cond holds. EFLAGS are updated by XALUR and XALUIR instructions.
+    pub fn gen_branch(&mut self, cond: Cond, sym: Sym) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load_symbol(tmp, sym)?;
+        self.gen(asm::jcc(cond, tmp))?;
+        self.free_temp(tmp)
+    }
+
+    // Update EFLAGS based on the value in reg
+    pub(crate) fn gen_test(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen(asm::or(tmp, reg, Register::R0))?;
+        self.free_temp(tmp)
+    }
+
+    pub fn gen_branch_zero(&mut self, reg: Register, sym: Sym) -> Result<(), DynAsmError> {
+        self.gen_test(reg)?;
+        self.gen_branch(Cond::Z, sym)
+    }
+
+    pub fn gen_branch_nonzero(&mut self, reg: Register, sym: Sym) -> Result<(), DynAsmError> {
+        self.gen_test(reg)?;
+        self.gen_branch(Cond::NZ, sym)
+    }
+
     pub fn gen_cond_jump(&mut self, cond: Register, t: Sym, f: Sym) -> Result<(), DynAsmError> {
         let r0 = Register::R0;
-        let r4 = Register::R4;
-        let r5 = Register::R5;
+        let r4 = self.scratch()?;
+        let r5 = self.scratch()?;
 
         // AND condition with one to make sure its 0 or 1
         self.gen(asm::andi(r5, cond, Const::Number(1)))?;
@@ -227,19 +371,15 @@ impl DynAsm {
         self.gen(asm::sub(r4, r0, r5))?;
 
         // AND in true branch sym address
-        let low = self.sym_ref_imm_low(t)?;
-        self.gen(asm::xandil(r4, r4, low))?;
-        let high = self.sym_ref_imm_high(t)?;
-        self.gen(asm::xandiu(r4, r4, high))?;
+        self.gen_sym_imm(asm::xandil(r4, r4, 0), t, SymRefKind::LowImm)?;
+        self.gen_sym_imm(asm::xandiu(r4, r4, 0), t, SymRefKind::HighImm)?;
 
         // Map 0 to 0xFFFF_FFFF and 1 to 0x0000_0000
         self.gen(asm::subi(r5, r5, Const::Number(1)))?;
 
         // AND in false branch sym address
-        let low = self.sym_ref_imm_low(f)?;
-        self.gen(asm::xandil(r5, r5, low))?;
-        let high = self.sym_ref_imm_high(f)?;
-        self.gen(asm::xandiu(r5, r5, high))?;
+        self.gen_sym_imm(asm::xandil(r5, r5, 0), f, SymRefKind::LowImm)?;
+        self.gen_sym_imm(asm::xandiu(r5, r5, 0), f, SymRefKind::HighImm)?;
 
         // Merge jump locations
         self.gen(asm::or(r4, r4, r5))?;
@@ -247,48 +387,337 @@ impl DynAsm {
         // Jump
         self.gen(asm::j(r4))?;
 
-        Ok(())
+        self.free_temp(r5)?;
+        self.free_temp(r4)
     }
 
     pub fn gen_call(&mut self, sym: Sym) -> Result<(), DynAsmError> {
-        let r4 = Register::R4;
+        let r4 = self.scratch()?;
         self.gen_load_symbol(r4, sym)?;
         self.gen(asm::puship(Size::Bits32))?;
         self.gen(asm::j(r4))?;
-        Ok(())
+        self.free_temp(r4)
     }
 
     pub fn gen_ret(&mut self) -> Result<(), DynAsmError> {
-        let r4 = Register::R4;
+        let r4 = self.scratch()?;
         self.gen(asm::popsp(Size::Bits32, r4))?;
         self.gen(asm::addi(r4, r4, Const::Number(6)))?;
         self.gen(asm::j(r4))?;
+        self.free_temp(r4)
+    }
+
+    pub fn gen_read_eflags(&mut self, dst: Register) -> Result<(), DynAsmError> {
+        self.gen(asm::cfc2(dst, Cp2Reg::EFLAGS))
+    }
+
+    pub fn gen_write_eflags(&mut self, src: Register) -> Result<(), DynAsmError> {
+        self.gen(asm::ctc2(Cp2Reg::EFLAGS, src))
+    }
+
+    // Lower 32 bits of the time stamp counter. There is no gen_read_tsc(lo, hi) until TSC_U has
+    // been located in CP2, the upper half can only be read with x86 rdtsc.
+    pub fn gen_read_tsc_low(&mut self, lo: Register) -> Result<(), DynAsmError> {
+        self.gen(asm::cfc2(lo, Cp2Reg::TSC_L))
+    }
+
+    // dst = [sel:sym]
+    pub fn gen_load_mem(
+        &mut self,
+        size: Size,
+        dst: Register,
+        sym: Sym,
+        sel: Sel,
+    ) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load_symbol(tmp, sym)?;
+        let zero = Offset::Number(0);
+        self.gen(asm::load(size, dst, tmp, zero, sel, AddrSize::Bits32))?;
+        self.free_temp(tmp)
+    }
+
+    // [sel:sym] = src
+    pub fn gen_store_mem(
+        &mut self,
+        size: Size,
+        src: Register,
+        sym: Sym,
+        sel: Sel,
+    ) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load_symbol(tmp, sym)?;
+        let zero = Offset::Number(0);
+        self.gen(asm::store(size, src, tmp, zero, sel, AddrSize::Bits32))?;
+        self.free_temp(tmp)
+    }
+
+    // Read entry index of a descriptor table. The reference claims that these accesses are not
+    // protection checked.
+    pub fn gen_read_desc(
+        &mut self,
+        lo: Register,
+        hi: Register,
+        table: Sel,
+        index: u16,
+    ) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load_with(tmp, u32::from(index) * 8, Goal::Size)?;
+        for (dst, offset) in [(lo, 0), (hi, 4)] {
+            let offset = Offset::Number(offset);
+            self.gen(asm::load(
+                Size::Bits32,
+                dst,
+                tmp,
+                offset,
+                table,
+                AddrSize::Bits32,
+            ))?;
+        }
+        self.free_temp(tmp)
+    }
+
+    pub fn gen_write_desc(
+        &mut self,
+        table: Sel,
+        index: u16,
+        lo: Register,
+        hi: Register,
+    ) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load_with(tmp, u32::from(index) * 8, Goal::Size)?;
+        for (src, offset) in [(lo, 0), (hi, 4)] {
+            let offset = Offset::Number(offset);
+            self.gen(asm::store(
+                Size::Bits32,
+                src,
+                tmp,
+                offset,
+                table,
+                AddrSize::Bits32,
+            ))?;
+        }
+        self.free_temp(tmp)
+    }
+
+    // Load segment register seg like mov sreg would, the TI bit of selector picks the GDT or LDT.
+    // XLDESC is assumed to fill only the hidden descriptor part, so the selector is written as well.
+    // Experimental: neither assumption has been checked on hardware and the simulator can't run it.
+    pub fn gen_load_seg(&mut self, seg: Register, selector: u16) -> Result<(), DynAsmError> {
+        if !(Register::CS.0..=Register::GS.0).contains(&seg.0) {
+            return Err(DynAsmError::NotSegmentRegister(seg));
+        }
+        let table = match selector & 4 {
+            0 => Sel::GDT,
+            _ => Sel::LDT,
+        };
+
+        let tmp = self.scratch()?;
+        self.gen_load_with(tmp, u32::from(selector & !7), Goal::Size)?;
+        self.gen(asm::ldesc(seg, tmp, Offset::Number(0), table))?;
+        self.gen_load_with(tmp, selector.into(), Goal::Size)?;
+        self.gen(asm::or(seg, tmp, Register::R0))?;
+        self.free_temp(tmp)
+    }
+
+    // Jump to sym and continue in x86 mode
+    pub fn gen_jump_x86(&mut self, sym: Sym) -> Result<(), DynAsmError> {
+        let tmp = self.scratch()?;
+        self.gen_load_symbol(tmp, sym)?;
+        self.gen(asm::jx86(tmp))?;
+        self.free_temp(tmp)
+    }
+
+    // Switch to x86 mode, execution continues directly after the exit
+    pub fn gen_exit_x86(&mut self) -> Result<(), DynAsmError> {
+        let sym = self.new_sym();
+        self.gen_jump_x86(sym)?;
+        self.set_sym_here(sym)
+    }
+
+    // Raw x86 machine code, only valid after switching to x86 mode
+    pub fn gen_x86(&mut self, bytes: &[u8]) {
+        self.push_item(Item::X86(bytes.to_vec()));
+    }
+
+    // x86 instruction with a 32bit symbol field at field_offset
+    fn gen_x86_sym(
+        &mut self,
+        bytes: Vec<u8>,
+        field_offset: usize,
+        sym: Sym,
+        kind: SymRefKind,
+    ) -> Result<(), DynAsmError> {
+        self.check_sym(sym)?;
+        self.push_item(Item::X86Sym(bytes, field_offset, sym, kind));
+        Ok(())
+    }
+
+    pub fn gen_x86_mov_imm(&mut self, dst: Register, imm: u32) -> Result<(), DynAsmError> {
+        self.gen_x86(&x86::mov_imm(dst, imm)?);
         Ok(())
     }
 
+    pub fn gen_x86_mov_symbol(&mut self, dst: Register, sym: Sym) -> Result<(), DynAsmError> {
+        let bytes = x86::mov_imm(dst, 0)?;
+        self.gen_x86_sym(bytes, 1, sym, SymRefKind::Abs32)
+    }
+
+    pub fn gen_x86_push(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        self.gen_x86(&x86::push(reg)?);
+        Ok(())
+    }
+
+    pub fn gen_x86_push_imm(&mut self, imm: u32) {
+        self.gen_x86(&x86::push_imm(imm));
+    }
+
+    pub fn gen_x86_push_symbol(&mut self, sym: Sym) -> Result<(), DynAsmError> {
+        self.gen_x86_sym(x86::push_imm(0), 1, sym, SymRefKind::Abs32)
+    }
+
+    pub fn gen_x86_pop(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        self.gen_x86(&x86::pop(reg)?);
+        Ok(())
+    }
+
+    pub fn gen_x86_call(&mut self, sym: Sym) -> Result<(), DynAsmError> {
+        self.gen_x86_sym(x86::call_rel(0), 1, sym, SymRefKind::Rel32)
+    }
+
+    pub fn gen_x86_call_reg(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        self.gen_x86(&x86::call_reg(reg)?);
+        Ok(())
+    }
+
+    pub fn gen_x86_ret(&mut self) {
+        self.gen_x86(&x86::ret());
+    }
+
+    // out dx, al/ax/eax
+    pub fn gen_x86_out(&mut self, width: Width) {
+        self.gen_x86(&x86::out(width));
+    }
+
+    // in al/ax/eax, dx
+    pub fn gen_x86_in(&mut self, width: Width) {
+        self.gen_x86(&x86::inp(width));
+    }
+
+    pub fn gen_x86_cpuid(&mut self) {
+        self.gen_x86(&x86::cpuid());
+    }
+
+    pub fn gen_x86_rdtsc(&mut self) {
+        self.gen_x86(&x86::rdtsc());
+    }
+
+    // Switch to AIS mode and continue at the address in reg, clobbers EAX
+    pub fn gen_x86_jmpai(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        self.gen_x86(&x86::jmpai(reg)?);
+        Ok(())
+    }
+
+    // Switch to AIS mode and continue at sym, clobbers EAX
+    pub fn gen_x86_jmpai_symbol(&mut self, sym: Sym) -> Result<(), DynAsmError> {
+        self.gen_x86_mov_symbol(Register::EAX, sym)?;
+        self.gen_x86_jmpai(Register::EAX)
+    }
+
+    // Switch from x86 to AIS mode. The header is position independent, so it can be used at any point, it does clobber EAX.
+    pub fn gen_enter_ais(&mut self) {
+        self.gen_x86(HEADER);
+    }
+
     pub fn gen_header(&mut self) {
-        self.memory.extend_from_slice(HEADER);
+        self.gen_enter_ais();
     }
 
     pub fn gen_footer(&mut self) {
-        self.memory.extend_from_slice(FOOTER);
+        self.gen_x86(FOOTER);
     }
 
     pub fn memory(&self) -> &Vec<u8> {
-        &self.memory
+        &self.output().memory
+    }
+
+    fn x86_range_end(&self, offset: u32) -> Option<u32> {
+        self.output()
+            .x86
+            .iter()
+            .find(|(start, end)| (*start..*end).contains(&offset))
+            .map(|(_, end)| *end)
+    }
+
+    // Check the whole program, translator state set with set_translator_state applies from the start
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        self.validate_memory(self.memory())
+    }
+
+    fn validate_memory(&self, memory: &[u8]) -> Vec<Diagnostic> {
+        let mut validator = Validator::new();
+        for dep in &self.translator {
+            validator.establish(*dep);
+        }
+
+        let mut diagnostics = Vec::new();
+        let mut offset = 0;
+        while offset < memory.len() as u32 {
+            if let Some(end) = self.x86_range_end(offset) {
+                offset = end;
+                continue;
+            }
+
+            match Instruction::decode(&memory[offset as usize..]) {
+                Ok((instr, size)) => {
+                    for reason in validator.check(&instr) {
+                        diagnostics.push(Diagnostic { offset, reason });
+                    }
+                    offset += size as u32;
+                }
+                Err(_) => {
+                    let reason = Reason::Undecodable;
+                    diagnostics.push(Diagnostic { offset, reason });
+
+                    // Continue at the next AIS header or x86 code
+                    offset += 1;
+                    while offset < memory.len() as u32
+                        && !memory[offset as usize..].starts_with(&[0x62, 0x80])
+                        && self.x86_range_end(offset).is_none()
+                    {
+                        offset += 1;
+                    }
+                }
+            }
+        }
+        diagnostics
     }
 
     pub fn dump(&self) {
-        let mut bytes = &self.memory[HEADER.len()..self.memory.len() - FOOTER.len()];
-        loop {
-            if bytes.is_empty() {
-                break;
+        let memory = self.memory();
+        let mut offset = 0;
+        while offset < memory.len() as u32 {
+            let start: usize = offset.try_into().unwrap();
+
+            if let Some(end) = self.x86_range_end(offset) {
+                println!("x86: {:02X?}", &memory[start..end as usize]);
+                offset = end;
+                continue;
             }
 
-            match Instruction::decode(bytes) {
+            match Instruction::decode(&memory[start..]) {
                 Ok((i, size)) => {
                     println!("{:?}", i);
-                    bytes = &bytes[size..];
+                    if let Some(offset) = i
+                        .offset
+                        .filter(|o| !translator::dependencies(*o).is_empty())
+                    {
+                        let value = TranslatorState::default().resolve(offset).unwrap();
+                        println!(
+                            "    {:?} = {:#X} with the default translator state",
+                            offset, value
+                        );
+                    }
+                    offset += size as u32;
                 }
                 Err(e) => {
                     println!("{:?}", e);
@@ -298,3 +727,225 @@ impl DynAsm {
         }
     }
 }
+
+#[test]
+fn exit_x86_continues_after_jump() {
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.gen_header();
+    asm.gen_exit_x86().unwrap();
+    asm.gen_x86(&[0x90]); // nop
+    asm.gen_enter_ais();
+
+    // Load of R4 is two instructions, followed by the XJ
+    let exit = HEADER.len() + 3 * 6;
+    let (load_low, _) = Instruction::decode(&asm.memory()[HEADER.len()..]).unwrap();
+    assert_eq!(load_low.imm, Some(exit as u16));
+    assert_eq!(asm.memory()[exit], 0x90);
+    assert_eq!(asm.output().x86, vec![(0, 11), (29, 41)]);
+}
+
+#[test]
+fn x86_symbol_fixups() {
+    let mut asm = DynAsm::new(0x48_0000);
+    let func = asm.new_sym();
+    asm.gen_x86_call(func).unwrap();
+    asm.gen_x86_mov_symbol(Register::EBX, func).unwrap();
+    asm.set_sym_here(func).unwrap();
+    asm.gen_x86_ret();
+
+    assert_eq!(
+        asm.memory(),
+        &[0xE8, 5, 0, 0, 0, 0xBB, 0x0A, 0x00, 0x48, 0x00, 0xC3]
+    );
+}
+
+#[test]
+fn helpers_avoid_reserved_registers() {
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.regs().reserve(Register::R4).unwrap();
+
+    let sym = asm.new_sym_here();
+    asm.gen_jump(sym).unwrap();
+
+    // The address has a zero low half, so it is loaded with a single ORIU
+    let (xj, _) = Instruction::decode(&asm.memory()[6..]).unwrap();
+    assert_eq!(xj.rt, Some(Register::R5));
+}
+
+#[test]
+fn special_offsets_warn_without_state() {
+    use crate::ais::{Offset, Size};
+
+    let mut asm = DynAsm::new(0x48_0000);
+    let dfos = asm::push(Size::Bits32, Register::EAX, Register::EDI, Offset::DFOS);
+    asm.gen(dfos).unwrap();
+    assert_eq!(asm.warnings().len(), 2);
+
+    asm.set_translator_state(Dependency::OperandSize);
+    asm.gen_write_eflags(Register::EAX).unwrap();
+    asm.gen(dfos).unwrap();
+    assert_eq!(asm.warnings().len(), 2);
+}
+
+#[test]
+fn temps_spill_when_exhausted() {
+    let mut asm = DynAsm::new(0x48_0000);
+    let temps: Vec<Register> = (0..5).map(|_| asm.alloc_temp().unwrap()).collect();
+    assert_eq!(temps[4], Register::EAX);
+    assert_eq!(asm.memory().len(), 6); // push eax
+
+    asm.free_temp(Register::EAX).unwrap();
+    assert_eq!(asm.memory().len(), 12); // pop eax
+    assert!(asm.free_temp(Register::EAX).is_err());
+}
+
+#[test]
+fn memory_through_symbols() {
+    use crate::sim::{NoIo, Sim};
+
+    let mut asm = DynAsm::new(0x1000);
+    let data = asm.new_sym();
+    asm.gen_load(Register::EAX, 0x1234_5678).unwrap();
+    asm.gen_store_mem(Size::Bits32, Register::EAX, data, Sel::DS)
+        .unwrap();
+    asm.gen_load_mem(Size::Bits8H, Register::ECX, data, Sel::FLAT)
+        .unwrap();
+    asm.gen(asm::jx86(Register::R0)).unwrap();
+    asm.set_sym_here(data).unwrap();
+    asm.gen_x86(&[0; 4]);
+
+    let mut sim = Sim::new(NoIo);
+    sim.load(0x1000, asm.memory());
+    sim.run(0x1000, 10).unwrap();
+    let addr = asm.sym_addr(data).unwrap().unwrap();
+    assert_eq!(sim.read(addr, 4), 0x1234_5678);
+    assert_eq!(sim.reg(Register::ECX), 0x7800);
+}
+
+#[test]
+fn descriptor_helpers() {
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.gen_read_desc(Register::EAX, Register::EDX, Sel::GDT, 2)
+        .unwrap();
+    asm.gen_load_seg(Register::DS, 0x10).unwrap();
+    assert!(asm.gen_load_seg(Register::EAX, 0x10).is_err());
+
+    let instrs: Vec<Instruction> = asm
+        .memory()
+        .chunks(6)
+        .map(|bytes| Instruction::decode(bytes).unwrap().0)
+        .collect();
+    assert_eq!(instrs[0].imm, Some(16));
+    assert!(matches!(
+        instrs[2].function,
+        Some(crate::ais::Function::Xls(_, _, Size::Bits32, Sel::GDT))
+    ));
+    assert_eq!(instrs[4].opcode, crate::ais::Opcode::XLDESC);
+    assert_eq!(instrs[4].rs, Some(Register::DS));
+}
+
+#[test]
+fn strict_mode_rejects_errors() {
+    use crate::ais::AddrSize;
+
+    let (eax, edx) = (Register::EAX, Register::EDX);
+    let store = asm::store(
+        Size::Bits32,
+        eax,
+        edx,
+        Offset::Number(0),
+        Sel::CS,
+        AddrSize::Bits32,
+    );
+
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.gen_exit_x86().unwrap();
+    asm.gen_enter_ais();
+    asm.gen(store).unwrap();
+    assert_eq!(asm.warnings().len(), 1);
+
+    let diagnostics = asm.validate();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].reason, Reason::StoreThroughCs);
+    let end = asm.memory().len() as u32;
+    assert_eq!(diagnostics[0].offset, end - 6);
+
+    asm.set_strict(true);
+    assert!(matches!(
+        asm.gen(store),
+        Err(DynAsmError::Invalid(Reason::StoreThroughCs))
+    ));
+}
+
+#[test]
+fn validate_continues_past_undecodable() {
+    use crate::ais::AddrSize;
+
+    let (eax, edx) = (Register::EAX, Register::EDX);
+    let load = asm::load(
+        Size::Bits32,
+        eax,
+        edx,
+        Offset::DISP,
+        Sel::DS,
+        AddrSize::Bits32,
+    );
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.gen(asm::or(eax, eax, edx)).unwrap();
+    asm.gen(asm::xori(Register::R0, eax, 1)).unwrap();
+    asm.gen(load).unwrap();
+
+    let mut memory = asm.memory().to_vec();
+    memory[0] = 0x90;
+    let reasons: Vec<(u32, Reason)> = asm
+        .validate_memory(&memory)
+        .iter()
+        .map(|d| (d.offset, d.reason))
+        .collect();
+    assert_eq!(
+        reasons,
+        [
+            (0, Reason::Undecodable),
+            (6, Reason::WritesR0),
+            (12, Reason::TranslatorState(Dependency::Disp)),
+        ]
+    );
+
+    // Special offsets without their translator state are rejected in strict mode
+    asm.set_strict(true);
+    assert!(matches!(
+        asm.gen(load),
+        Err(DynAsmError::Invalid(Reason::TranslatorState(
+            Dependency::Disp
+        )))
+    ));
+}
+
+#[test]
+fn descriptor_table_access() {
+    use crate::sim::{NoIo, Sim};
+
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_read_desc(Register::EAX, Register::EDX, Sel::GDT, 2)
+        .unwrap();
+    asm.gen_write_desc(Sel::LDT, 1, Register::EAX, Register::EDX)
+        .unwrap();
+    asm.gen(asm::jx86(Register::R0)).unwrap();
+
+    let mut sim = Sim::new(NoIo);
+    sim.load(0x1000, asm.memory());
+    sim.tables[0] = Some(0x8000);
+    sim.tables[1] = Some(0x9000);
+    sim.write(0x8010, 4, 0x0000_FFFF);
+    sim.write(0x8014, 4, 0x00CF_9300);
+    sim.run(0x1000, 20).unwrap();
+    assert_eq!(sim.reg(Register::EAX), 0x0000_FFFF);
+    assert_eq!(sim.reg(Register::EDX), 0x00CF_9300);
+    assert_eq!(sim.read(0x9008, 4), 0x0000_FFFF);
+    assert_eq!(sim.read(0x900C, 4), 0x00CF_9300);
+
+    // A table without a base is not modelled
+    let mut sim = Sim::new(NoIo);
+    sim.load(0x1000, asm.memory());
+    assert!(sim.run(0x1000, 20).is_err());
+}
@@ -0,0 +1,157 @@
+// Structured control flow on top of DynAsm, the labels are managed internally.
+
+use crate::ais::{Cond, Register};
+use crate::asm;
+use crate::dynasm::{DynAsm, DynAsmError};
+
+impl DynAsm {
+    // Run then when cond holds for the current EFLAGS, otherwise run otherwise
+    pub fn if_cond(
+        &mut self,
+        cond: Cond,
+        then: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+        otherwise: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        let else_ = self.new_sym();
+        let end = self.new_sym();
+
+        self.gen_branch(cond.negate(), else_)?;
+        then(self)?;
+        self.gen_jump(end)?;
+
+        self.set_sym_here(else_)?;
+        otherwise(self)?;
+        self.set_sym_here(end)
+    }
+
+    pub fn if_nonzero(
+        &mut self,
+        reg: Register,
+        then: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+        otherwise: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        self.with_reserved(reg, |asm| {
+            asm.gen_test(reg)?;
+            asm.if_cond(Cond::NZ, then, otherwise)
+        })
+    }
+
+    pub fn if_zero(
+        &mut self,
+        reg: Register,
+        then: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+        otherwise: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        self.with_reserved(reg, |asm| {
+            asm.gen_test(reg)?;
+            asm.if_cond(Cond::Z, then, otherwise)
+        })
+    }
+
+    // Run body while reg is nonzero, the test is done before each iteration
+    pub fn while_nonzero(
+        &mut self,
+        reg: Register,
+        body: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        self.with_reserved(reg, |asm| {
+            let end = asm.new_sym();
+            let top = asm.new_sym_here();
+
+            asm.gen_branch_zero(reg, end)?;
+            body(asm)?;
+            asm.gen_jump(top)?;
+
+            asm.set_sym_here(end)
+        })
+    }
+
+    // Run body with reg counting from 0 up to, but not including, n
+    pub fn for_range(
+        &mut self,
+        reg: Register,
+        n: u32,
+        body: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        self.gen_load(reg, 0)?;
+        if n == 0 {
+            return Ok(());
+        }
+
+        self.with_reserved(reg, |asm| {
+            let top = asm.new_sym_here();
+            body(asm)?;
+
+            let tmp = asm.scratch()?;
+            asm.gen_load(tmp, 1)?;
+            asm.gen(asm::add(reg, reg, tmp))?;
+            asm.gen_load(tmp, n)?;
+            asm.gen(asm::sub(tmp, reg, tmp))?;
+            asm.free_temp(tmp)?;
+
+            asm.gen_branch(Cond::NZ, top)
+        })
+    }
+
+    // Keep helpers from using reg as scratch
+    fn with_reserved(
+        &mut self,
+        reg: Register,
+        body: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        let state = self.regs().hold(reg)?;
+        let result = body(self);
+        self.regs().restore(reg, state);
+        result
+    }
+}
+
+#[test]
+fn for_range_branches_back_to_body() {
+    use crate::ais::Instruction;
+
+    let mut asm = DynAsm::new(0x48_0000);
+    let r4 = Register::R4;
+
+    asm.for_range(r4, 3, |asm| {
+        asm.gen(asm::or(Register::EAX, Register::EAX, r4))
+    })
+    .unwrap();
+
+    let memory = asm.memory();
+    let instr = |i: usize| Instruction::decode(&memory[i * 6..]).unwrap().0;
+
+    // Counter lives in R4, so the helpers use R5
+    assert_eq!(instr(1).rd, Some(Register::EAX));
+    let (low, high) = (instr(6), instr(7));
+    assert_eq!(low.rt, Some(Register::R5));
+    assert_eq!(high.imm, Some(0x48));
+    assert_eq!(low.imm, Some(6));
+}
+
+#[test]
+fn counter_is_released_when_body_fails() {
+    let mut asm = DynAsm::new(0x48_0000);
+    let r4 = Register::R4;
+
+    let result = asm.for_range(r4, 3, |_| Err(DynAsmError::MacroArgument));
+    assert!(matches!(result, Err(DynAsmError::MacroArgument)));
+    assert!(asm.regs().is_free(r4));
+}
+
+#[test]
+fn temps_can_be_loop_counters() {
+    let mut asm = DynAsm::new(0x48_0000);
+    let counter = asm.alloc_temp().unwrap();
+
+    asm.for_range(counter, 3, |asm| {
+        // The counter is not handed out again inside the body
+        let tmp = asm.alloc_temp()?;
+        assert_ne!(tmp, counter);
+        asm.free_temp(tmp)
+    })
+    .unwrap();
+
+    asm.free_temp(counter).unwrap();
+    assert!(asm.regs().is_free(counter));
+}
@@ -0,0 +1,390 @@
+// Subroutines with a stack frame calling convention.
+//
+// Frame layout, EBP is the frame pointer and all slots are 32bit:
+//   EBP + 8 + 4 * i     stack argument i, pushed by the caller and removed by the caller
+//   EBP + 4             return address, pushed by XPUSHIP
+//   EBP + 0             frame pointer of the caller
+//   EBP - 4 * (j + 1)   callee-saved register j
+//   below the saved registers, local slot k
+//
+// The return value is passed in EAX.
+
+use crate::ais::{AddrSize, Offset, Register, Sel, Size};
+use crate::asm;
+use crate::dynasm::{DynAsm, DynAsmError, Sym};
+use crate::regalloc::State;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Arg {
+    Reg(Register),
+    Stack,
+}
+
+pub struct Function {
+    sym: Sym,
+    epilogue: Sym,
+    saved: Vec<Register>,
+    args: Vec<Arg>,
+    locals: u32,
+}
+
+impl Function {
+    pub fn new(asm: &mut DynAsm) -> Self {
+        Self {
+            sym: asm.new_sym(),
+            epilogue: asm.new_sym(),
+            saved: Vec::new(),
+            args: Vec::new(),
+            locals: 0,
+        }
+    }
+
+    // Registers that are preserved for the caller
+    pub fn saved(mut self, regs: &[Register]) -> Self {
+        self.saved = regs.to_vec();
+        self
+    }
+
+    pub fn args(mut self, args: &[Arg]) -> Self {
+        self.args = args.to_vec();
+        self
+    }
+
+    // Number of 32bit local slots
+    pub fn locals(mut self, locals: u32) -> Self {
+        self.locals = locals;
+        self
+    }
+
+    pub fn sym(&self) -> Sym {
+        self.sym
+    }
+
+    fn stack_args(&self) -> u32 {
+        self.args
+            .iter()
+            .filter(|arg| matches!(arg, Arg::Stack))
+            .count() as u32
+    }
+
+    fn saved_size(&self) -> u32 {
+        4 * self.saved.len() as u32
+    }
+
+    fn reg_args(&self) -> impl Iterator<Item = Register> + '_ {
+        self.args.iter().filter_map(|arg| match arg {
+            Arg::Reg(reg) => Some(*reg),
+            Arg::Stack => None,
+        })
+    }
+
+    // Keep helpers from using regs as scratch while gen runs
+    fn with_held(
+        asm: &mut DynAsm,
+        regs: impl IntoIterator<Item = Register>,
+        gen: impl FnOnce(&mut DynAsm) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        // Registers that are never handed out need no holding
+        let mut held: Vec<(Register, State)> = Vec::new();
+        for reg in regs {
+            if held.iter().all(|x| x.0 != reg) {
+                if let Ok(state) = asm.regs().hold(reg) {
+                    held.push((reg, state));
+                }
+            }
+        }
+
+        let result = gen(asm);
+        for (reg, state) in held.into_iter().rev() {
+            asm.regs().restore(reg, state);
+        }
+        result
+    }
+
+    // Place the function at the current location
+    pub fn gen_body(
+        &self,
+        asm: &mut DynAsm,
+        body: impl FnOnce(&mut DynAsm, &Function) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        asm.set_sym_here(self.sym)?;
+        Self::with_held(asm, self.reg_args(), |asm| self.gen_frame(asm, body))
+    }
+
+    fn gen_frame(
+        &self,
+        asm: &mut DynAsm,
+        body: impl FnOnce(&mut DynAsm, &Function) -> Result<(), DynAsmError>,
+    ) -> Result<(), DynAsmError> {
+        let esp = Register::ESP;
+        let ebp = Register::EBP;
+
+        // Prologue
+        asm.gen(asm::pushsp(Size::Bits32, ebp))?;
+        asm.gen(asm::or(ebp, esp, Register::R0))?;
+        for reg in &self.saved {
+            asm.gen(asm::pushsp(Size::Bits32, *reg))?;
+        }
+        if self.locals > 0 {
+            asm.gen_sub_imm(esp, 4 * self.locals)?;
+        }
+
+        body(asm, self)?;
+
+        // Epilogue
+        asm.set_sym_here(self.epilogue)?;
+        asm.gen(asm::or(esp, ebp, Register::R0))?;
+        if !self.saved.is_empty() {
+            asm.gen_sub_imm(esp, self.saved_size())?;
+        }
+        for reg in self.saved.iter().rev() {
+            asm.gen(asm::popsp(Size::Bits32, *reg))?;
+        }
+        asm.gen(asm::popsp(Size::Bits32, ebp))?;
+        asm.gen_ret()
+    }
+
+    // Return from anywhere in the body
+    pub fn gen_return(&self, asm: &mut DynAsm) -> Result<(), DynAsmError> {
+        asm.gen_jump(self.epilogue)
+    }
+
+    // Call with the values in regs as arguments, in the order of args()
+    pub fn gen_call(&self, asm: &mut DynAsm, regs: &[Register]) -> Result<(), DynAsmError> {
+        if regs.len() != self.args.len() {
+            return Err(DynAsmError::ArgumentCount);
+        }
+
+        // The argument registers and the values must survive until the callee is entered
+        let regs_held = self.reg_args().chain(regs.iter().copied());
+        Self::with_held(asm, regs_held, |asm| self.gen_pass(asm, regs))?;
+
+        let stack_args = self.stack_args();
+        if stack_args > 0 {
+            asm.gen_add_imm(Register::ESP, 4 * stack_args)?;
+        }
+
+        Ok(())
+    }
+
+    fn gen_pass(&self, asm: &mut DynAsm, regs: &[Register]) -> Result<(), DynAsmError> {
+        // Stack arguments are pushed last to first
+        for (arg, reg) in self.args.iter().zip(regs).rev() {
+            if let Arg::Stack = arg {
+                asm.gen(asm::pushsp(Size::Bits32, *reg))?;
+            }
+        }
+
+        let moves: Vec<(Register, Register)> = self
+            .args
+            .iter()
+            .zip(regs)
+            .filter_map(|(arg, reg)| match arg {
+                Arg::Reg(dst) if dst != reg => Some((*dst, *reg)),
+                _ => None,
+            })
+            .collect();
+
+        // Register arguments are moved in order, unless a source is the target of an earlier move,
+        // as in f(ecx, eax) for args in eax and ecx. Then they all go through the stack.
+        let overlap = (0..moves.len()).any(|i| moves[..i].iter().any(|x| x.0 == moves[i].1));
+        if overlap {
+            for (_, src) in &moves {
+                asm.gen(asm::pushsp(Size::Bits32, *src))?;
+            }
+            for (dst, _) in moves.iter().rev() {
+                asm.gen(asm::popsp(Size::Bits32, *dst))?;
+            }
+        } else {
+            for (dst, src) in &moves {
+                asm.gen(asm::or(*dst, *src, Register::R0))?;
+            }
+        }
+
+        asm.gen_call(self.sym)
+    }
+
+    // Distance below EBP of local slot
+    fn local_offset(&self, slot: u32) -> u32 {
+        self.saved_size() + 4 * (slot + 1)
+    }
+
+    // Base and offset of the slot at EBP + distance, through a scratch register when the offset
+    // has no encoding
+    fn frame_slot(
+        &self,
+        asm: &mut DynAsm,
+        distance: i32,
+    ) -> Result<(Register, Offset, Option<Register>), DynAsmError> {
+        let offset = i8::try_from(distance).ok().map(Offset::Number);
+        if let Some(offset) = offset.filter(|x| TryInto::<u8>::try_into(*x).is_ok()) {
+            return Ok((Register::EBP, offset, None));
+        }
+
+        let tmp = asm.scratch()?;
+        asm.gen_load(tmp, distance as u32)?;
+        asm.gen(asm::add(tmp, Register::EBP, tmp))?;
+        Ok((tmp, Offset::Number(0), Some(tmp)))
+    }
+
+    fn gen_frame_load(
+        &self,
+        asm: &mut DynAsm,
+        dst: Register,
+        distance: i32,
+    ) -> Result<(), DynAsmError> {
+        let (base, offset, tmp) = self.frame_slot(asm, distance)?;
+        let load = asm::load(Size::Bits32, dst, base, offset, Sel::SS, AddrSize::Bits32);
+        asm.gen(load)?;
+        tmp.map_or(Ok(()), |tmp| asm.free_temp(tmp))
+    }
+
+    pub fn gen_load_local(
+        &self,
+        asm: &mut DynAsm,
+        dst: Register,
+        slot: u32,
+    ) -> Result<(), DynAsmError> {
+        self.gen_frame_load(asm, dst, -(self.local_offset(slot) as i32))
+    }
+
+    pub fn gen_store_local(
+        &self,
+        asm: &mut DynAsm,
+        slot: u32,
+        src: Register,
+    ) -> Result<(), DynAsmError> {
+        let (base, offset, tmp) = self.frame_slot(asm, -(self.local_offset(slot) as i32))?;
+        let store = asm::store(Size::Bits32, src, base, offset, Sel::SS, AddrSize::Bits32);
+        asm.gen(store)?;
+        tmp.map_or(Ok(()), |tmp| asm.free_temp(tmp))
+    }
+
+    // Load stack argument index, counted among the stack arguments only
+    pub fn gen_load_arg(
+        &self,
+        asm: &mut DynAsm,
+        dst: Register,
+        index: u32,
+    ) -> Result<(), DynAsmError> {
+        self.gen_frame_load(asm, dst, 8 + 4 * index as i32)
+    }
+}
+
+#[test]
+fn prologue_sets_up_frame() {
+    use crate::ais::{Instruction, Opcode};
+
+    let mut asm = DynAsm::new(0x48_0000);
+    let func = Function::new(&mut asm)
+        .saved(&[Register::EBX])
+        .args(&[Arg::Reg(Register::EAX), Arg::Stack])
+        .locals(1);
+
+    func.gen_body(&mut asm, |asm, func| {
+        func.gen_load_arg(asm, Register::EBX, 0)?;
+        func.gen_store_local(asm, 0, Register::EBX)
+    })
+    .unwrap();
+
+    let memory = asm.memory();
+    let instr = |i: usize| Instruction::decode(&memory[i * 6..]).unwrap().0;
+
+    let push_ebp = instr(0);
+    assert_eq!(push_ebp.opcode, Opcode::XPUSH);
+    assert_eq!(push_ebp.rs, Some(Register::EBP));
+
+    let set_frame = instr(1);
+    assert_eq!(set_frame.rd, Some(Register::EBP));
+    assert_eq!(set_frame.rs, Some(Register::ESP));
+
+    let push_ebx = instr(2);
+    assert_eq!(push_ebx.rs, Some(Register::EBX));
+}
+
+#[test]
+fn call_passes_swapped_arguments() {
+    use crate::sim::{NoIo, Sim, Stop};
+
+    const BASE: u32 = 0x48_0000;
+    const STACK: u32 = 0x10_0000;
+    let (eax, ebx, ecx, edx) = (Register::EAX, Register::EBX, Register::ECX, Register::EDX);
+
+    // f(a, b, c) = a - b + c, with a in EAX, b in ECX and c on the stack
+    let mut asm = DynAsm::new(BASE);
+    let func =
+        Function::new(&mut asm)
+            .saved(&[ebx])
+            .args(&[Arg::Reg(eax), Arg::Reg(ecx), Arg::Stack]);
+
+    let start = asm.new_sym();
+    asm.gen_jump(start).unwrap();
+    func.gen_body(&mut asm, |asm, func| {
+        func.gen_load_arg(asm, ebx, 0)?;
+        asm.gen(asm::sub(eax, eax, ecx))?;
+        asm.gen(asm::add(eax, eax, ebx))
+    })
+    .unwrap();
+    asm.set_sym_here(start).unwrap();
+    func.gen_call(&mut asm, &[ecx, eax, edx]).unwrap();
+    let end = asm.memory().len() as u32;
+    asm.gen_footer();
+
+    let mut sim = Sim::new(NoIo);
+    sim.load(BASE, asm.memory());
+    sim.set_reg(Register::ESP, STACK);
+    sim.set_reg(Register::EBP, 0x1234);
+    sim.set_reg(ebx, 0x5678);
+    sim.set_reg(ecx, 100);
+    sim.set_reg(eax, 30);
+    sim.set_reg(edx, 5);
+
+    assert_eq!(sim.run(BASE, 1000).unwrap(), Stop::X86(BASE + end));
+    assert_eq!(sim.reg(eax), 75);
+    assert_eq!(sim.reg(Register::ESP), STACK);
+    assert_eq!(sim.reg(Register::EBP), 0x1234);
+    assert_eq!(sim.reg(ebx), 0x5678);
+}
+
+#[test]
+fn argument_in_scratch_register() {
+    use crate::sim::{NoIo, Sim, Stop};
+
+    const BASE: u32 = 0x48_0000;
+    const STACK: u32 = 0x10_0000;
+    let (eax, ecx, r4) = (Register::EAX, Register::ECX, Register::R4);
+
+    // f(a, b) = a + b, a arrives in R4, which helpers use as scratch when it is free. Locals 0 and
+    // 3 are reached with an encoded offset and through a scratch register.
+    let mut asm = DynAsm::new(BASE);
+    let func = Function::new(&mut asm)
+        .args(&[Arg::Reg(r4), Arg::Stack])
+        .locals(4);
+
+    let start = asm.new_sym();
+    asm.gen_jump(start).unwrap();
+    func.gen_body(&mut asm, |asm, func| {
+        func.gen_store_local(asm, 0, r4)?;
+        func.gen_load_arg(asm, ecx, 0)?;
+        func.gen_store_local(asm, 3, ecx)?;
+        func.gen_load_local(asm, eax, 0)?;
+        func.gen_load_local(asm, ecx, 3)?;
+        asm.gen(asm::add(eax, eax, ecx))
+    })
+    .unwrap();
+    asm.set_sym_here(start).unwrap();
+    func.gen_call(&mut asm, &[eax, ecx]).unwrap();
+    let end = asm.memory().len() as u32;
+    asm.gen_footer();
+    assert!(asm.regs().is_free(r4));
+
+    let mut sim = Sim::new(NoIo);
+    sim.load(BASE, asm.memory());
+    sim.set_reg(Register::ESP, STACK);
+    sim.set_reg(eax, 30);
+    sim.set_reg(ecx, 12);
+
+    assert_eq!(sim.run(BASE, 1000).unwrap(), Stop::X86(BASE + end));
+    assert_eq!(sim.reg(eax), 42);
+    assert_eq!(sim.reg(Register::ESP), STACK);
+}
@@ -0,0 +1,134 @@
+// Output file formats for assembled code that is loaded at a fixed base address.
+
+fn u16le(out: &mut Vec<u8>, x: u16) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn u32le(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+const EHSIZE: u32 = 52;
+const PHENTSIZE: u32 = 32;
+const SHENTSIZE: u32 = 40;
+const SHSTRTAB: &[u8] = b"\0.text\0.shstrtab\0";
+
+// 32bit x86 executable with the code in a single .text section at base, which is also the entry
+pub fn elf32(base: u32, code: &[u8]) -> Vec<u8> {
+    let size = code.len() as u32;
+    let text = EHSIZE + PHENTSIZE;
+    let strtab = text + size;
+    let shoff = (strtab + SHSTRTAB.len() as u32 + 3) & !3;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0x7F, b'E', b'L', b'F', 1, 1, 1, 0]);
+    out.resize(16, 0);
+    u16le(&mut out, 2); // ET_EXEC
+    u16le(&mut out, 3); // EM_386
+    u32le(&mut out, 1);
+    u32le(&mut out, base);
+    u32le(&mut out, EHSIZE);
+    u32le(&mut out, shoff);
+    u32le(&mut out, 0);
+    u16le(&mut out, EHSIZE as u16);
+    u16le(&mut out, PHENTSIZE as u16);
+    u16le(&mut out, 1);
+    u16le(&mut out, SHENTSIZE as u16);
+    u16le(&mut out, 3);
+    u16le(&mut out, 2);
+
+    // PT_LOAD, readable and executable
+    for x in [1, text, base, base, size, size, 5, 1] {
+        u32le(&mut out, x);
+    }
+
+    out.extend_from_slice(code);
+    out.extend_from_slice(SHSTRTAB);
+    out.resize(shoff as usize, 0);
+
+    // Null, .text as PROGBITS with ALLOC and EXECINSTR, .shstrtab as STRTAB
+    let sections = [
+        [0; 10],
+        [1, 1, 6, base, text, size, 0, 0, 1, 0],
+        [7, 3, 0, 0, strtab, SHSTRTAB.len() as u32, 0, 0, 1, 0],
+    ];
+    for x in sections.iter().flatten() {
+        u32le(&mut out, *x);
+    }
+
+    out
+}
+
+fn hex_record(out: &mut String, kind: u8, addr: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+
+    let sum = bytes.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+    bytes.push(sum.wrapping_neg());
+
+    out.push(':');
+    for b in bytes {
+        out.push_str(&format!("{:02X}", b));
+    }
+    out.push('\n');
+}
+
+// Intel HEX with 16 byte data records and extended linear address records
+pub fn intel_hex(base: u32, code: &[u8]) -> String {
+    let mut out = String::new();
+    let mut upper = None;
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let addr = base.wrapping_add(offset as u32);
+        if upper != Some(addr >> 16) {
+            upper = Some(addr >> 16);
+            hex_record(&mut out, 4, 0, &((addr >> 16) as u16).to_be_bytes());
+        }
+
+        // Records don't cross a 64KiB boundary
+        let room = 0x1_0000 - (addr & 0xFFFF) as usize;
+        let len = 16.min(room).min(code.len() - offset);
+        hex_record(&mut out, 0, addr as u16, &code[offset..offset + len]);
+        offset += len;
+    }
+
+    hex_record(&mut out, 1, 0, &[]);
+    out
+}
+
+#[test]
+fn elf_has_code_at_base() {
+    let code = [0x62, 0x80, 0x47, 0x00, 0x10, 0x18, 0xC3];
+    let elf = elf32(0x48_0000, &code);
+
+    let u32_at = |x: usize| u32::from_le_bytes(elf[x..x + 4].try_into().unwrap());
+    assert_eq!(&elf[..4], b"\x7FELF");
+    assert_eq!(u32_at(24), 0x48_0000);
+
+    // Program header points at the code
+    let offset = u32_at(52 + 4) as usize;
+    assert_eq!(&elf[offset..offset + code.len()], code);
+    assert_eq!(u32_at(52 + 8), 0x48_0000);
+
+    // Section headers are last
+    assert_eq!(u32_at(32) as usize + 3 * 40, elf.len());
+}
+
+#[test]
+fn hex_records() {
+    let hex = intel_hex(0x4_FFFE, &[0x62, 0x80, 0x47]);
+    let lines: Vec<&str> = hex.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            ":020000040004F6",
+            ":02FFFE0062801F",
+            ":020000040005F5",
+            ":0100000047B8",
+            ":00000001FF",
+        ]
+    );
+}
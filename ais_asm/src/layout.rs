@@ -0,0 +1,234 @@
+// Intermediate representation of a DynAsm program and its layout.
+//
+// References to symbols stay symbolic until the program is laid out. A load of a symbol address
+// starts out as a single ORI or ORIU and grows to two instructions when the address does not fit
+// in one immediate. Items only ever grow, so repeating the layout until nothing changes reaches a
+// fixed point, and forward references end up as compact as backward ones.
+
+use crate::ais::{Instruction, Register};
+use crate::asm;
+use crate::dynasm::{DynAsmError, Sym, SymRefKind};
+
+const INSTR_SIZE: u32 = 6;
+
+// Offset ranges that contain x86 code instead of AIS instructions
+pub(crate) type X86Ranges = Vec<(u32, u32)>;
+
+#[derive(Debug, Clone)]
+pub(crate) enum Item {
+    Instr(Instruction),
+    SymImm(Instruction, Sym, SymRefKind), // Immediate is the high or low half of sym
+    SymLoad(Register, Sym),               // Load the address of sym
+    X86(Vec<u8>),
+    X86Sym(Vec<u8>, usize, Sym, SymRefKind), // 32bit field at the offset
+    Label(Sym),
+}
+
+impl Item {
+    // Instruction that can be changed without affecting a symbol reference
+    pub(crate) fn plain(&self) -> Option<&Instruction> {
+        match self {
+            Item::Instr(instr) => Some(instr),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Layout {
+    pub(crate) symbols: Vec<Option<u32>>,
+    long: Vec<bool>, // Symbol loads that need two instructions
+}
+
+fn imm_high(addr: u32) -> u16 {
+    (addr >> 16) as u16
+}
+
+fn imm_low(addr: u32) -> u16 {
+    addr as u16
+}
+
+fn fits_one(addr: Option<u32>) -> bool {
+    match addr {
+        Some(addr) => imm_high(addr) == 0 || imm_low(addr) == 0,
+        None => false,
+    }
+}
+
+fn size(item: &Item, long: bool) -> u32 {
+    match item {
+        Item::Instr(_) | Item::SymImm(..) => INSTR_SIZE,
+        Item::SymLoad(..) if long => 2 * INSTR_SIZE,
+        Item::SymLoad(..) => INSTR_SIZE,
+        Item::X86(bytes) | Item::X86Sym(bytes, ..) => bytes.len() as u32,
+        Item::Label(_) => 0,
+    }
+}
+
+impl Layout {
+    pub(crate) fn new(base: u32, items: &[Item], symbols: usize) -> Self {
+        let mut layout = Self {
+            symbols: vec![None; symbols],
+            long: vec![false; items.len()],
+        };
+
+        loop {
+            let mut addr = base;
+            for (item, long) in items.iter().zip(&layout.long) {
+                if let Item::Label(sym) = item {
+                    layout.symbols[sym.0] = Some(addr);
+                }
+                addr += size(item, *long);
+            }
+
+            let mut changed = false;
+            for (item, long) in items.iter().zip(layout.long.iter_mut()) {
+                if let Item::SymLoad(_, sym) = item {
+                    if !*long && !fits_one(layout.symbols[sym.0]) {
+                        *long = true;
+                        changed = true;
+                    }
+                }
+            }
+
+            if !changed {
+                return layout;
+            }
+        }
+    }
+
+    // Undefined symbols are encoded as 0
+    fn addr(&self, sym: Sym) -> u32 {
+        self.symbols[sym.0].unwrap_or(0)
+    }
+
+    // Machine code and the ranges that contain x86 code
+    pub(crate) fn encode(
+        &self,
+        base: u32,
+        items: &[Item],
+    ) -> Result<(Vec<u8>, X86Ranges), DynAsmError> {
+        let mut memory: Vec<u8> = Vec::new();
+        let mut x86 = X86Ranges::new();
+
+        for (item, long) in items.iter().zip(&self.long) {
+            let start = memory.len() as u32;
+
+            match item {
+                Item::Instr(instr) => memory.extend(instr.encode()?),
+                Item::SymImm(instr, sym, kind) => {
+                    let mut instr = *instr;
+                    instr.imm = Some(match kind {
+                        SymRefKind::HighImm => imm_high(self.addr(*sym)),
+                        _ => imm_low(self.addr(*sym)),
+                    });
+                    memory.extend(instr.encode()?);
+                }
+                Item::SymLoad(dst, sym) => {
+                    let addr = self.addr(*sym);
+                    let r0 = Register::R0;
+                    let instrs = match (*long, imm_high(addr)) {
+                        (true, high) => vec![
+                            asm::xori(*dst, r0, imm_low(addr)),
+                            asm::xoriu(*dst, *dst, high),
+                        ],
+                        (false, 0) => vec![asm::xori(*dst, r0, imm_low(addr))],
+                        (false, high) => vec![asm::xoriu(*dst, r0, high)],
+                    };
+                    for instr in instrs {
+                        memory.extend(instr.encode()?);
+                    }
+                }
+                Item::X86(bytes) => memory.extend_from_slice(bytes),
+                Item::X86Sym(bytes, field_offset, sym, kind) => {
+                    let field = start + *field_offset as u32;
+                    let addr = self.addr(*sym);
+                    let value = match kind {
+                        // Relative to the end of the field
+                        SymRefKind::Rel32 => addr.wrapping_sub(base + field + 4),
+                        _ => addr,
+                    };
+
+                    let mut bytes = bytes.clone();
+                    bytes[*field_offset..*field_offset + 4].copy_from_slice(&value.to_le_bytes());
+                    memory.extend(bytes);
+                }
+                Item::Label(_) => {}
+            }
+
+            let end = memory.len() as u32;
+            if matches!(item, Item::X86(_) | Item::X86Sym(..)) {
+                match x86.last_mut() {
+                    Some((_, last_end)) if *last_end == start => *last_end = end,
+                    _ => x86.push((start, end)),
+                }
+            }
+        }
+
+        Ok((memory, x86))
+    }
+}
+
+#[test]
+fn forward_loads_are_short_when_they_fit() {
+    use crate::dynasm::DynAsm;
+
+    let mut asm = DynAsm::new(0x1000);
+    let end = asm.new_sym();
+    asm.gen_jump(end).unwrap();
+    asm.set_sym_here(end).unwrap();
+
+    let memory = asm.memory();
+    assert_eq!(memory.len(), 2 * INSTR_SIZE as usize);
+    let (load, _) = Instruction::decode(memory).unwrap();
+    assert_eq!(load.imm, Some(0x100C));
+}
+
+#[test]
+fn growing_loads_push_later_loads_out_of_range() {
+    use crate::dynasm::DynAsm;
+
+    // Only a fits at first, growing the load of b moves a out of range as well
+    let mut asm = DynAsm::new(0xFFE6);
+    let a = asm.new_sym();
+    let b = asm.new_sym();
+    asm.gen_jump(a).unwrap();
+    asm.gen_jump(b).unwrap();
+    asm.set_sym_here(a).unwrap();
+    asm.gen(asm::or(Register::EAX, Register::EAX, Register::R0))
+        .unwrap();
+    asm.set_sym_here(b).unwrap();
+
+    assert_eq!(asm.memory().len(), 7 * INSTR_SIZE as usize);
+    assert_eq!(asm.sym_addr(a).unwrap(), Some(0x1_000A));
+    assert_eq!(asm.sym_addr(b).unwrap(), Some(0x1_0010));
+}
+
+#[test]
+fn sym_addr_does_not_change_the_output() {
+    use crate::dynasm::DynAsm;
+    use crate::peephole::Peephole;
+
+    let program = |query: bool| {
+        let mut asm = DynAsm::new(0xFFE6);
+        asm.set_peephole(Peephole::all());
+        let a = asm.new_sym();
+        let b = asm.new_sym();
+        asm.gen_jump(a).unwrap();
+        asm.gen_load(Register::EAX, 0).unwrap();
+        if query {
+            assert_eq!(asm.sym_addr(a).unwrap(), None);
+        }
+        asm.gen_load(Register::EAX, 0).unwrap();
+        asm.gen_jump(b).unwrap();
+        asm.set_sym_here(a).unwrap();
+        if query {
+            asm.sym_addr(a).unwrap();
+        }
+        asm.gen(asm::or(Register::EAX, Register::EAX, Register::R0))
+            .unwrap();
+        asm.set_sym_here(b).unwrap();
+        asm.memory().clone()
+    };
+
+    assert_eq!(program(true), program(false));
+}
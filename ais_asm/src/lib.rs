@@ -1,12 +1,25 @@
 pub mod ais;
 pub mod asm;
 pub mod decode;
+pub mod disasm;
 pub mod dynasm;
 pub mod encode;
-
-fn bit(word: u32, bit: u32) -> u32 {
-    (word >> bit) & 1
-}
+pub mod flow;
+pub mod func;
+pub mod image;
+mod layout;
+pub mod macros;
+pub mod parse;
+pub mod peephole;
+pub mod regalloc;
+pub mod sim;
+pub mod spec;
+pub mod stdlib;
+pub mod synth;
+pub mod translator;
+pub mod typed;
+pub mod validate;
+pub mod x86;
 
 fn bits(word: u32, high: u32, low: u32) -> u32 {
     let mask = (1 << (high - low + 1)) - 1;
@@ -0,0 +1,159 @@
+// Parameterised instruction sequences that are registered with DynAsm and expanded by name.
+//
+// Expansion is hygienic. Labels made inside a macro with new_sym() are always fresh, register
+// arguments are reserved so temporaries never alias them, and temporaries that the macro still
+// holds at the end of the expansion are freed.
+
+use crate::ais::Register;
+use crate::dynasm::{DynAsm, DynAsmError, Sym};
+
+#[derive(Debug, Copy, Clone)]
+pub enum MacroArg {
+    Reg(Register),
+    Imm(u32),
+    Sym(Sym),
+}
+
+impl MacroArg {
+    pub fn reg(&self) -> Result<Register, DynAsmError> {
+        match self {
+            MacroArg::Reg(reg) => Ok(*reg),
+            _ => Err(DynAsmError::MacroArgument),
+        }
+    }
+
+    pub fn imm(&self) -> Result<u32, DynAsmError> {
+        match self {
+            MacroArg::Imm(imm) => Ok(*imm),
+            _ => Err(DynAsmError::MacroArgument),
+        }
+    }
+
+    pub fn sym(&self) -> Result<Sym, DynAsmError> {
+        match self {
+            MacroArg::Sym(sym) => Ok(*sym),
+            _ => Err(DynAsmError::MacroArgument),
+        }
+    }
+}
+
+pub trait Macro {
+    fn expand(&self, asm: &mut DynAsm, args: &[MacroArg]) -> Result<(), DynAsmError>;
+}
+
+impl<F> Macro for F
+where
+    F: Fn(&mut DynAsm, &[MacroArg]) -> Result<(), DynAsmError>,
+{
+    fn expand(&self, asm: &mut DynAsm, args: &[MacroArg]) -> Result<(), DynAsmError> {
+        self(asm, args)
+    }
+}
+
+impl DynAsm {
+    pub fn gen_macro(&mut self, name: &str, args: &[MacroArg]) -> Result<(), DynAsmError> {
+        let mac = self.find_macro(name)?;
+
+        // Reserve register arguments that are not already held by someone
+        let mut reserved = Vec::new();
+        for arg in args {
+            if let MacroArg::Reg(reg) = arg {
+                if self.regs().is_free(*reg) {
+                    self.regs().reserve(*reg)?;
+                    reserved.push(*reg);
+                }
+            }
+        }
+
+        let temps = self.regs().temps();
+        let result = mac.expand(self, args);
+
+        // Also on failure, so the allocator is left as it was. Free leaked temporaries, last allocated first so spills are restored in order
+        let leaked: Vec<Register> = self
+            .regs()
+            .temps()
+            .into_iter()
+            .filter(|reg| !temps.contains(reg))
+            .collect();
+        for reg in leaked.into_iter().rev() {
+            self.free_temp(reg)?;
+        }
+
+        for reg in reserved {
+            self.regs().release(reg)?;
+        }
+
+        result
+    }
+}
+
+#[test]
+fn expansion_is_hygienic() {
+    use crate::ais::{Instruction, Offset, Size};
+    use crate::asm;
+
+    let mut asm = DynAsm::new(0x48_0000);
+
+    // Store reg at the next slot of the result buffer, the pointer is kept in EDX
+    asm.register_macro("store_result", |asm: &mut DynAsm, args: &[MacroArg]| {
+        let reg = args[0].reg()?;
+        let tmp = asm.alloc_temp()?;
+        asm.gen(asm::or(tmp, reg, Register::R0))?;
+        asm.gen(asm::push(
+            Size::Bits32,
+            tmp,
+            Register::EDX,
+            Offset::Number(4),
+        ))
+    });
+
+    asm.regs().release(Register::EAX).unwrap();
+    for reg in [Register::R4, Register::EAX] {
+        asm.gen_macro("store_result", &[MacroArg::Reg(reg)])
+            .unwrap();
+    }
+
+    assert!(asm.regs().is_free(Register::R4));
+    assert!(asm.regs().is_free(Register::R5));
+
+    let memory = asm.memory();
+    let instr = |i: usize| Instruction::decode(&memory[i * 6..]).unwrap().0;
+    assert_eq!(instr(0).rd, Some(Register::R5));
+    assert_eq!(instr(2).rd, Some(Register::R4));
+
+    assert!(matches!(
+        asm.gen_macro("missing", &[]),
+        Err(DynAsmError::UnknownMacro)
+    ));
+}
+
+#[test]
+fn arguments_are_released_when_expansion_fails() {
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.register_macro("fail", |_: &mut DynAsm, _: &[MacroArg]| {
+        Err(DynAsmError::MacroArgument)
+    });
+
+    let result = asm.gen_macro("fail", &[MacroArg::Reg(Register::R4)]);
+    assert!(matches!(result, Err(DynAsmError::MacroArgument)));
+    assert!(asm.regs().is_free(Register::R4));
+}
+
+#[test]
+fn temporaries_are_freed_when_expansion_fails() {
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.register_macro("fail", |asm: &mut DynAsm, _: &[MacroArg]| {
+        asm.alloc_temp()?;
+        asm.alloc_temp()?;
+        Err(DynAsmError::MacroArgument)
+    });
+
+    let kept = asm.alloc_temp().unwrap();
+    let free = |asm: &mut DynAsm| (0..32).map(|i| asm.regs().is_free(Register(i))).collect();
+    let before: Vec<bool> = free(&mut asm);
+
+    let result = asm.gen_macro("fail", &[MacroArg::Reg(Register::R5)]);
+    assert!(matches!(result, Err(DynAsmError::MacroArgument)));
+    assert_eq!(free(&mut asm), before);
+    assert_eq!(asm.regs().temps(), [kept]);
+}
@@ -0,0 +1,424 @@
+// Assembler for the text form written by disasm.
+//
+// One statement per line, ';' starts a comment and 'name:' defines a label. Besides the
+// instructions there are pseudo instructions that go through the DynAsm helpers:
+//   li reg, value    gen_load
+//   la reg, label    gen_load_symbol
+//   jmp label        gen_jump
+//   b.<cond> label   gen_branch, cond as in xj
+//   call label       gen_call
+//   ret              gen_ret
+//   .byte xx ...     x86 bytes, in hex
+
+use crate::ais::{Cond, Const, Field, Function, Instruction, Offset, Register, XJ_COND_ENABLE};
+use crate::decode;
+use crate::disasm;
+use crate::dynasm::{DynAsm, DynAsmError, Sym};
+use crate::spec::{self, Kind};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub enum ParseError {
+    UnknownMnemonic(String),
+    UnknownLayout(String), // Opcode without a layout in spec
+    Function(String),      // Suffixes that don't fit the function fields
+    OperandCount,
+    Register(String),
+    Number(String),
+    Const(String),
+    Offset(String),
+    Field(String), // Missing or unknown name=value field
+    UndefinedLabel(String),
+    DynAsmError(DynAsmError),
+}
+
+impl From<DynAsmError> for ParseError {
+    fn from(x: DynAsmError) -> Self {
+        Self::DynAsmError(x)
+    }
+}
+
+pub fn number(text: &str) -> Result<i64, ParseError> {
+    let err = || ParseError::Number(text.to_string());
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+
+    let value = match digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| err())?;
+
+    Ok(if negative { -value } else { value })
+}
+
+pub fn register(text: &str) -> Result<Register, ParseError> {
+    let err = || ParseError::Register(text.to_string());
+
+    if let Some(x) = spec::value(Kind::Register, text) {
+        return Ok(Register(x as u8));
+    }
+    let x: u8 = text
+        .strip_prefix('r')
+        .ok_or_else(err)?
+        .parse()
+        .map_err(|_| err())?;
+    Register::try_from(x).map_err(|_| err())
+}
+
+// Constants and offsets are looked up by their disasm text, so names, values and raw(n) all work
+fn constant(text: &str) -> Result<Const, ParseError> {
+    (0..32u8)
+        .filter_map(|x| Const::try_from(x).ok())
+        .find(|&c| disasm::constant(c) == text)
+        .ok_or(ParseError::Const(text.to_string()))
+}
+
+fn offset(text: &str) -> Result<Offset, ParseError> {
+    (0..32u8)
+        .filter_map(|x| Offset::try_from(x).ok())
+        .find(|&o| disasm::offset(o) == text)
+        .ok_or(ParseError::Offset(text.to_string()))
+}
+
+fn raw(text: &str) -> Option<&str> {
+    text.strip_prefix("raw(")?.strip_suffix(')')
+}
+
+fn function(
+    mnemonic: &str,
+    fields: &[spec::FieldSpec],
+    suffixes: &[&str],
+) -> Result<Option<Function>, ParseError> {
+    let err = || ParseError::Function(mnemonic.to_string());
+
+    if let [suffix] = suffixes {
+        if let Some(x) = raw(suffix) {
+            let x = number(x)?.try_into().map_err(|_| err())?;
+            return Ok(Some(Function::Raw(x)));
+        }
+    }
+    if fields.is_empty() && suffixes.is_empty() {
+        return Ok(None);
+    }
+    // The XJ condition is left out when none of its bits are set
+    let mut suffixes = suffixes.to_vec();
+    if let Some(i) = fields.iter().position(|x| x.kind == Kind::XjCond) {
+        if suffixes.len() + 1 == fields.len() {
+            suffixes.insert(i, "0");
+        }
+    }
+    if fields.len() != suffixes.len() {
+        return Err(err());
+    }
+
+    let mut values = Vec::new();
+    for (field, suffix) in fields.iter().zip(&suffixes) {
+        let value = match spec::value(field.kind, suffix) {
+            Some(value) => value,
+            None => number(suffix)?.try_into().map_err(|_| err())?,
+        };
+        values.push(value);
+    }
+    decode::function(fields, &values)
+        .map(Some)
+        .map_err(|_| err())
+}
+
+pub fn instruction(text: &str) -> Result<Instruction, ParseError> {
+    let text = text.trim();
+    let (head, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let mut suffixes: Vec<&str> = head.split('.').collect();
+    let mnemonic = suffixes.remove(0);
+
+    let spec = spec::mnemonic(mnemonic).ok_or(ParseError::UnknownMnemonic(head.to_string()))?;
+    let format = spec
+        .format
+        .ok_or(ParseError::UnknownLayout(mnemonic.to_string()))?;
+
+    let mut instr = Instruction::new(spec.opcode);
+    instr.function = function(mnemonic, format.function, &suffixes)?;
+
+    let operands: Vec<&str> = operands
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect();
+    if operands.len() != format.operands.len() {
+        return Err(ParseError::OperandCount);
+    }
+
+    for (field, text) in format.operands.iter().zip(operands) {
+        match field.field {
+            Field::RS => instr.rs = Some(register(text)?),
+            Field::RT => instr.rt = Some(register(text)?),
+            Field::RD => instr.rd = Some(register(text)?),
+            Field::Immediate => {
+                let x = number(text)?;
+                if !(-0x8000..=0xFFFF).contains(&x) {
+                    return Err(ParseError::Number(text.to_string()));
+                }
+                instr.imm = Some(x as u16);
+            }
+            Field::Const => instr.constant = Some(constant(text)?),
+            Field::Offset => instr.offset = Some(offset(text)?),
+            Field::Opcode | Field::Function => unreachable!(),
+        }
+    }
+
+    Ok(instr)
+}
+
+// Instruction from name=value pairs as listed by disasm::fields
+pub fn fields(pairs: &[(&str, &str)]) -> Result<Instruction, ParseError> {
+    let get = |name: &str| {
+        pairs
+            .iter()
+            .find(|x| x.0 == name)
+            .map(|x| x.1)
+            .ok_or(ParseError::Field(name.to_string()))
+    };
+
+    let mnemonic = get("opcode")?;
+    let spec = spec::mnemonic(mnemonic).ok_or(ParseError::UnknownMnemonic(mnemonic.to_string()))?;
+    let format = spec
+        .format
+        .ok_or(ParseError::UnknownLayout(mnemonic.to_string()))?;
+
+    for (name, _) in pairs {
+        let known = ["opcode", "function", "leftovers"].contains(name);
+        let known = known
+            || format
+                .operands
+                .iter()
+                .chain(format.function)
+                .any(|x| x.name == *name);
+        if !known {
+            return Err(ParseError::Field(name.to_string()));
+        }
+    }
+
+    let mut head = mnemonic.to_string();
+    match get("function") {
+        Ok(function) => head = format!("{}.{}", head, function),
+        Err(_) => {
+            for field in format.function {
+                head = format!("{}.{}", head, get(field.name)?);
+            }
+        }
+    }
+
+    let operands = format
+        .operands
+        .iter()
+        .map(|field| get(field.name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut instr = instruction(&format!("{} {}", head, operands.join(", ")))?;
+
+    if let Ok(leftovers) = get("leftovers") {
+        let x = number(leftovers)?;
+        instr.leftovers = x
+            .try_into()
+            .map_err(|_| ParseError::Number(x.to_string()))?;
+    }
+    Ok(instr)
+}
+
+// Symbol of every label, with the line it was first used on
+#[derive(Default)]
+struct Labels {
+    syms: HashMap<String, (Sym, usize)>,
+    line: usize,
+}
+
+impl Labels {
+    fn get(&mut self, asm: &mut DynAsm, name: &str) -> Sym {
+        let line = self.line;
+        self.syms
+            .entry(name.to_string())
+            .or_insert_with(|| (asm.new_sym(), line))
+            .0
+    }
+}
+
+fn statement(asm: &mut DynAsm, labels: &mut Labels, text: &str) -> Result<(), ParseError> {
+    let (head, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let args: Vec<&str> = rest
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect();
+
+    let cond: Option<Cond> = head
+        .strip_prefix("b.")
+        .and_then(|x| spec::value(Kind::XjCond, x))
+        .filter(|x| x >> 4 == XJ_COND_ENABLE)
+        .map(|x| num::FromPrimitive::from_u32(x & 0xF).unwrap());
+
+    match (head, &args[..], cond) {
+        (".byte", _, _) => {
+            let bytes = rest
+                .split_whitespace()
+                .map(|x| u8::from_str_radix(x, 16).map_err(|_| ParseError::Number(x.to_string())))
+                .collect::<Result<Vec<u8>, _>>()?;
+            asm.gen_x86(&bytes);
+        }
+        ("li", [reg, value], _) => {
+            let value = number(value)?;
+            if !(-0x8000_0000..=0xFFFF_FFFF).contains(&value) {
+                return Err(ParseError::Number(value.to_string()));
+            }
+            asm.gen_load(register(reg)?, value as u32)?
+        }
+        ("la", [reg, label], _) => {
+            let sym = labels.get(asm, label);
+            asm.gen_load_symbol(register(reg)?, sym)?
+        }
+        ("jmp", [label], _) => {
+            let sym = labels.get(asm, label);
+            asm.gen_jump(sym)?
+        }
+        ("call", [label], _) => {
+            let sym = labels.get(asm, label);
+            asm.gen_call(sym)?
+        }
+        ("ret", [], _) => asm.gen_ret()?,
+        (_, [label], Some(cond)) => {
+            let sym = labels.get(asm, label);
+            asm.gen_branch(cond, sym)?
+        }
+        ("li" | "la" | "jmp" | "call" | "ret", _, _) => return Err(ParseError::OperandCount),
+        _ => asm.gen(instruction(text)?)?,
+    }
+
+    Ok(())
+}
+
+// Assemble source into asm, errors carry the line number counting from one
+pub fn assemble(asm: &mut DynAsm, source: &str) -> Result<(), (usize, ParseError)> {
+    let mut labels = Labels::default();
+    let mut defined = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        labels.line = index + 1;
+        let at = |e| (index + 1, e);
+        let mut text = line.split(';').next().unwrap().trim();
+
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            let sym = labels.get(asm, label);
+            asm.set_sym_here(sym).map_err(|e| at(e.into()))?;
+            defined.push(label.to_string());
+            text = rest.trim();
+        }
+
+        if !text.is_empty() {
+            statement(asm, &mut labels, text).map_err(at)?;
+        }
+    }
+
+    let undefined = labels
+        .syms
+        .iter()
+        .filter(|(name, _)| !defined.contains(name))
+        .min_by_key(|(_, (_, line))| *line);
+    if let Some((name, (_, line))) = undefined {
+        return Err((*line, ParseError::UndefinedLabel(name.clone())));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn disasm_text_round_trips() {
+    use crate::ais::{AddrSize, Cp2Reg, Sel, Size};
+    use crate::asm;
+
+    let (eax, ecx, edx) = (Register::EAX, Register::ECX, Register::EDX);
+    let instrs = [
+        asm::xori(eax, ecx, 0x1234),
+        asm::xaddi(eax, ecx, -4i16 as u16),
+        asm::add(eax, ecx, edx),
+        asm::addi_nf(eax, ecx, Const::Number(5)),
+        asm::cfc2(eax, Cp2Reg::EFLAGS),
+        asm::pushsp(Size::Bits32, eax),
+        asm::load(
+            Size::Bits8H,
+            eax,
+            ecx,
+            Offset::Raw(13),
+            Sel::GDT,
+            AddrSize::Bits16,
+        ),
+        asm::jcc(Cond::NZ, eax),
+        asm::lead(eax, ecx, Offset::DFOS, AddrSize::Bits32, Size::SAS),
+    ];
+    #[cfg(feature = "unconfirmed")]
+    let instrs = instrs.into_iter().chain([asm::ior8(edx, eax)]);
+
+    for instr in instrs {
+        let text = disasm::disasm(&instr);
+        let parsed = instruction(&text).unwrap();
+        assert_eq!(
+            parsed.encode().unwrap(),
+            instr.encode().unwrap(),
+            "{}",
+            text
+        );
+
+        let fields = disasm::fields(&instr);
+        let pairs: Vec<(&str, &str)> = fields.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let parsed = self::fields(&pairs).unwrap();
+        assert_eq!(
+            parsed.encode().unwrap(),
+            instr.encode().unwrap(),
+            "{}",
+            text
+        );
+    }
+
+    assert!(matches!(
+        instruction("xalur.add eax, ecx, edx"),
+        Err(ParseError::Function(_))
+    ));
+    assert!(matches!(
+        instruction("xalur.add.word eax, ecx"),
+        Err(ParseError::OperandCount)
+    ));
+    assert!(matches!(
+        instruction("xaluir.add.word eax, ecx, 3"),
+        Err(ParseError::Const(_))
+    ));
+}
+
+#[test]
+fn source_runs_in_sim() {
+    use crate::sim::{NoIo, Sim};
+
+    let source = "
+        ; Sum 1..=4 into eax
+            li eax, 0
+            li ecx, 4
+        loop:
+            xalur.add.word eax, eax, ecx
+            xaluir.sub.word ecx, ecx, 1
+            b.nz loop
+            jmp done
+            .byte 90
+        done: xj.b32.always.x86 r0
+    ";
+
+    let mut asm = DynAsm::new(0x1000);
+    assemble(&mut asm, source).unwrap();
+
+    let mut sim = Sim::new(NoIo);
+    sim.load(0x1000, asm.memory());
+    sim.run(0x1000, 100).unwrap();
+    assert_eq!(sim.reg(Register::EAX), 10);
+
+    let mut asm = DynAsm::new(0x1000);
+    let err = assemble(&mut asm, "ret\njmp nowhere\nret\n").unwrap_err();
+    assert!(matches!(err, (2, ParseError::UndefinedLabel(_))));
+}
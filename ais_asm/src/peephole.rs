@@ -0,0 +1,389 @@
+// Peephole passes over the buffered instructions of DynAsm.
+//
+// DynAsm buffers instructions until the next label or x86 code, so a block is only entered at the
+// top and known register values can be tracked from the start of the block. Instructions that
+// refer to a symbol are never changed, their values are only known after layout.
+
+use crate::ais::{
+    Const, DpCntl, Function, Instruction, Opcode, Register, SubFunc, SubOpXalu, XjCond,
+};
+use crate::asm;
+use crate::layout::Item;
+use crate::sim::i_type_value;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Peephole {
+    pub zero_load: bool,    // Drop `ori rX, r0, 0` when rX is already zero
+    pub fold_high: bool,    // Drop loads of known values, and fold loads that share the high half
+    pub coalesce_add: bool, // Merge `addi rX, rX, a` + `addi rX, rX, b` when a + b is a constant
+}
+
+impl Peephole {
+    pub fn all() -> Self {
+        Self {
+            zero_load: true,
+            fold_high: true,
+            coalesce_add: true,
+        }
+    }
+}
+
+// Instruction counts going into and coming out of a pass, summed over all blocks
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PassStats {
+    pub before: usize,
+    pub after: usize,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PeepholeStats {
+    pub zero_load: PassStats,
+    pub fold_high: PassStats,
+    pub coalesce_add: PassStats,
+}
+
+// Registers written by an instruction, None when not known
+pub fn writes(instr: &Instruction) -> Option<Vec<Register>> {
+    let regs = match instr.opcode {
+        _ if instr.is_i_type() => vec![instr.rt?],
+        Opcode::XALU | Opcode::XALUR | Opcode::XALUI | Opcode::XALUIR => vec![instr.rd?],
+        Opcode::XPOP => vec![instr.rs?, instr.rt?],
+        Opcode::XPUSH | Opcode::XPUSHIP => vec![instr.rt?],
+        Opcode::XLEAD | Opcode::XLEAI | Opcode::XIOR | Opcode::XL => vec![instr.rs?],
+        Opcode::XIOW | Opcode::XJ | Opcode::XS => vec![],
+        // Only CFC2 is known to write a register
+        Opcode::XMISC => match instr.function {
+            Some(Function::Xmisc(SubFunc::CFC2, _)) => vec![instr.rt?],
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(regs)
+}
+
+struct Known([Option<u32>; 32]);
+
+impl Known {
+    fn new() -> Self {
+        let mut known = [None; 32];
+        known[0] = Some(0);
+        Self(known)
+    }
+
+    fn get(&self, reg: Register) -> Option<u32> {
+        self.0[reg.0 as usize]
+    }
+
+    fn set(&mut self, reg: Register, value: Option<u32>) {
+        if reg != Register::R0 {
+            self.0[reg.0 as usize] = value;
+        }
+    }
+
+    // Value an I type instruction produces, when its input is known
+    fn eval(&self, item: &Item) -> Option<u32> {
+        let instr = item.plain().filter(|instr| instr.is_i_type())?;
+        i_type_value(instr.opcode, self.get(instr.rs?)?, instr.imm?)
+    }
+
+    fn apply(&mut self, item: &Item) {
+        let instr = match item {
+            Item::Instr(instr) | Item::SymImm(instr, ..) => instr,
+            Item::SymLoad(dst, _) => {
+                self.set(*dst, None);
+                return;
+            }
+            _ => {
+                *self = Self::new();
+                return;
+            }
+        };
+
+        // Code after an unconditional jump is only reached from elsewhere, e.g. when a call returns
+        if let Some(Function::Xj(_, XjCond::Always, _)) = instr.function {
+            *self = Self::new();
+            return;
+        }
+
+        if let Some(value) = self.eval(item) {
+            self.set(instr.rt.unwrap(), Some(value));
+            return;
+        }
+
+        match writes(instr) {
+            Some(regs) => regs.into_iter().for_each(|reg| self.set(reg, None)),
+            None => *self = Self::new(),
+        }
+    }
+}
+
+fn zero_load(block: Vec<Item>) -> Vec<Item> {
+    let mut known = Known::new();
+    let mut out = Vec::new();
+
+    for item in block {
+        let redundant = item.plain().is_some_and(|instr| {
+            instr.opcode == Opcode::ORI
+                && instr.rs == Some(Register::R0)
+                && instr.imm == Some(0)
+                && known.get(instr.rt.unwrap()) == Some(0)
+        });
+
+        if !redundant {
+            known.apply(&item);
+            out.push(item);
+        }
+    }
+
+    out
+}
+
+// `ori rX, r0, low` + `oriu rX, rX, high`, returns rX and the loaded value
+fn full_load(first: &Item, second: &Item) -> Option<(Register, u32)> {
+    let (a, b) = (first.plain()?, second.plain()?);
+    let pair = a.opcode == Opcode::ORI
+        && a.rs == Some(Register::R0)
+        && b.opcode == Opcode::ORIU
+        && b.rs == a.rt
+        && b.rt == a.rt;
+
+    if pair {
+        Some((a.rt?, u32::from(b.imm?) << 16 | u32::from(a.imm?)))
+    } else {
+        None
+    }
+}
+
+fn fold_high(block: Vec<Item>) -> Vec<Item> {
+    let mut known = Known::new();
+    let mut out = Vec::new();
+    let mut items = block.into_iter().peekable();
+
+    while let Some(item) = items.next() {
+        if let Some((reg, value)) = items.peek().and_then(|next| full_load(&item, next)) {
+            if let Some(old) = known.get(reg).filter(|old| old >> 16 == value >> 16) {
+                items.next();
+                if old != value {
+                    let folded = Item::Instr(asm::xxori(reg, reg, (old ^ value) as u16));
+                    known.apply(&folded);
+                    out.push(folded);
+                }
+                continue;
+            }
+        }
+
+        let redundant = match (known.eval(&item), item.plain().and_then(|instr| instr.rt)) {
+            (Some(value), Some(rt)) => known.get(rt) == Some(value),
+            _ => false,
+        };
+
+        if !redundant {
+            known.apply(&item);
+            out.push(item);
+        }
+    }
+
+    out
+}
+
+// Register and constant of `addi rX, rX, c`
+fn add_const(item: &Item) -> Option<(Opcode, Register, i8)> {
+    let instr = item.plain()?;
+    match (instr.opcode, instr.function, instr.constant) {
+        (
+            Opcode::XALUI | Opcode::XALUIR,
+            Some(Function::Xalu(SubOpXalu::ADD, DpCntl::Word)),
+            Some(Const::Number(c)),
+        ) if instr.rs == instr.rd => Some((instr.opcode, instr.rd?, c)),
+        _ => None,
+    }
+}
+
+// True when the EFLAGS left by an instruction are overwritten before anything can read them
+fn flags_dead(rest: &[Item]) -> bool {
+    for item in rest {
+        let instr = match item {
+            Item::Instr(instr) | Item::SymImm(instr, ..) => instr,
+            _ => continue,
+        };
+        match (instr.opcode, instr.function) {
+            (
+                Opcode::XALUR | Opcode::XALUIR,
+                Some(Function::Xalu(
+                    SubOpXalu::ADD
+                    | SubOpXalu::SUB
+                    | SubOpXalu::AND
+                    | SubOpXalu::OR
+                    | SubOpXalu::XOR,
+                    _,
+                )),
+            ) => return true,
+            (Opcode::XJ | Opcode::XMISC | Opcode::XALUR | Opcode::XALUIR, _) => return false,
+            _ => {}
+        }
+    }
+
+    // Flags may be live at the end of the block
+    false
+}
+
+fn coalesce_add(block: Vec<Item>) -> Vec<Item> {
+    let mut out: Vec<Item> = Vec::new();
+
+    for (i, item) in block.iter().enumerate() {
+        let merged = match (out.last().and_then(add_const), add_const(item)) {
+            (Some((op_a, reg_a, a)), Some((op_b, reg_b, b))) if op_a == op_b && reg_a == reg_b => a
+                .checked_add(b)
+                .map(Const::Number)
+                .filter(|c| TryInto::<u8>::try_into(*c).is_ok())
+                .filter(|_| op_a == Opcode::XALUI || flags_dead(&block[i + 1..])),
+            _ => None,
+        };
+
+        match merged {
+            Some(c) => {
+                if let Some(Item::Instr(instr)) = out.last_mut() {
+                    instr.constant = Some(c);
+                }
+            }
+            None => out.push(item.clone()),
+        }
+    }
+
+    out
+}
+
+fn pass(
+    enabled: bool,
+    stats: &mut PassStats,
+    block: Vec<Item>,
+    f: fn(Vec<Item>) -> Vec<Item>,
+) -> Vec<Item> {
+    if !enabled {
+        return block;
+    }
+
+    stats.before += block.len();
+    let block = f(block);
+    stats.after += block.len();
+    block
+}
+
+pub(crate) fn optimize(
+    config: &Peephole,
+    stats: &mut PeepholeStats,
+    block: Vec<Item>,
+) -> Vec<Item> {
+    let block = pass(config.zero_load, &mut stats.zero_load, block, zero_load);
+    let block = pass(config.fold_high, &mut stats.fold_high, block, fold_high);
+    pass(
+        config.coalesce_add,
+        &mut stats.coalesce_add,
+        block,
+        coalesce_add,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ais::{Cond, Register};
+    use crate::dynasm::DynAsm;
+
+    const EAX: Register = Register::EAX;
+
+    fn decode_all(asm: &DynAsm) -> Vec<Instruction> {
+        asm.memory()
+            .chunks(6)
+            .map(|bytes| Instruction::decode(bytes).unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn zero_load_drops_second_clear() {
+        let mut asm = DynAsm::new(0x48_0000);
+        asm.set_peephole(Peephole {
+            zero_load: true,
+            ..Default::default()
+        });
+        asm.gen_load(EAX, 0).unwrap();
+        asm.gen_load(EAX, 0).unwrap();
+
+        assert_eq!(decode_all(&asm).len(), 1);
+        let stats = asm.peephole_stats();
+        assert_eq!(
+            stats.zero_load,
+            PassStats {
+                before: 2,
+                after: 1
+            }
+        );
+        assert_eq!(stats.fold_high, PassStats::default());
+    }
+
+    #[test]
+    fn sym_addr_does_not_split_blocks() {
+        let mut asm = DynAsm::new(0x48_0000);
+        asm.set_peephole(Peephole::all());
+        let start = asm.new_sym_here();
+        asm.gen_load(EAX, 0).unwrap();
+        assert_eq!(asm.sym_addr(start).unwrap(), Some(0x48_0000));
+        asm.gen_load(EAX, 0).unwrap();
+
+        assert_eq!(decode_all(&asm).len(), 1);
+    }
+
+    #[test]
+    fn fold_high_reuses_loaded_address() {
+        let mut asm = DynAsm::new(0x48_0000);
+        asm.set_peephole(Peephole::all());
+        asm.gen_load(EAX, 0x48_1234).unwrap();
+        asm.gen(asm::jcc(Cond::Z, EAX)).unwrap();
+        asm.gen_load(EAX, 0x48_123A).unwrap();
+        asm.gen(asm::jcc(Cond::S, EAX)).unwrap();
+        asm.gen_load(EAX, 0x48_123A).unwrap();
+        asm.gen(asm::jcc(Cond::O, EAX)).unwrap();
+
+        // ori + oriu + xj, xori + xj, xj
+        let instrs = decode_all(&asm);
+        assert_eq!(instrs.len(), 6);
+        assert_eq!(instrs[3].opcode, Opcode::XORI);
+        assert_eq!(instrs[3].imm, Some(0x1234 ^ 0x123A));
+        assert_eq!(
+            asm.peephole_stats().fold_high,
+            PassStats {
+                before: 9,
+                after: 6
+            }
+        );
+    }
+
+    #[test]
+    fn writes_only_known_xmisc() {
+        use crate::ais::Cp2Reg;
+
+        assert_eq!(writes(&asm::cfc2(EAX, Cp2Reg::EFLAGS)), Some(vec![EAX]));
+        let mut other = asm::cfc2(EAX, Cp2Reg::EFLAGS);
+        other.function = Some(Function::Raw(3 << 6));
+        assert_eq!(writes(&other), None);
+    }
+
+    #[test]
+    fn coalesce_add_keeps_live_flags() {
+        let mut asm = DynAsm::new(0x48_0000);
+        asm.set_peephole(Peephole::all());
+        asm.gen(asm::addi(EAX, EAX, Const::Number(1))).unwrap();
+        asm.gen(asm::addi(EAX, EAX, Const::Number(5))).unwrap();
+        assert_eq!(decode_all(&asm).len(), 2);
+
+        let mut asm = DynAsm::new(0x48_0000);
+        asm.set_peephole(Peephole::all());
+        asm.gen(asm::addi(EAX, EAX, Const::Number(1))).unwrap();
+        asm.gen(asm::addi(EAX, EAX, Const::Number(5))).unwrap();
+        asm.gen(asm::or(EAX, EAX, Register::R0)).unwrap();
+
+        let instrs = decode_all(&asm);
+        assert_eq!(instrs.len(), 2);
+        assert!(matches!(instrs[0].constant, Some(Const::Number(6))));
+    }
+}
@@ -0,0 +1,153 @@
+// Register bookkeeping for DynAsm.
+//
+// Registers are either free, reserved by user code, or handed out as a temporary.
+// R4..R7 start out free, the x86 registers start out reserved, user code can release them to
+// make them available for temporaries. R0 and ESP are never handed out.
+//
+// When no free register is left, a reserved register can be spilled to the stack and handed out.
+// Spills are restored in LIFO order, so they must not be live across jumps.
+
+use crate::ais::Register;
+use crate::dynasm::DynAsmError;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum State {
+    Unavailable,
+    Free,
+    Reserved,
+    Temp,
+    Spilled,
+}
+
+pub struct RegAlloc {
+    state: [State; 32],
+    spills: Vec<Register>,
+}
+
+// Allocation preference, AIS scratch registers before x86 registers
+const ORDER: [Register; 11] = [
+    Register::R4,
+    Register::R5,
+    Register::R6,
+    Register::R7,
+    Register::EAX,
+    Register::ECX,
+    Register::EDX,
+    Register::EBX,
+    Register::EBP,
+    Register::ESI,
+    Register::EDI,
+];
+
+impl Default for RegAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegAlloc {
+    pub fn new() -> Self {
+        let mut state = [State::Unavailable; 32];
+        for reg in ORDER {
+            state[reg.0 as usize] = State::Reserved;
+        }
+        for reg in [Register::R4, Register::R5, Register::R6, Register::R7] {
+            state[reg.0 as usize] = State::Free;
+        }
+
+        Self {
+            state,
+            spills: Vec::new(),
+        }
+    }
+
+    fn state(&self, reg: Register) -> Result<State, DynAsmError> {
+        self.state
+            .get(reg.0 as usize)
+            .copied()
+            .ok_or(DynAsmError::RegisterUnavailable(reg))
+    }
+
+    fn set(&mut self, reg: Register, state: State) {
+        self.state[reg.0 as usize] = state;
+    }
+
+    // User code holds a value in reg, it will not be handed out unless it is spilled. A temporary
+    // becomes a reserved register.
+    pub fn reserve(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        self.hold(reg).map(|_| ())
+    }
+
+    // Reserve reg, returns the state to put back with restore
+    pub(crate) fn hold(&mut self, reg: Register) -> Result<State, DynAsmError> {
+        match self.state(reg)? {
+            state @ (State::Free | State::Reserved | State::Temp) => {
+                self.set(reg, State::Reserved);
+                Ok(state)
+            }
+            _ => Err(DynAsmError::RegisterUnavailable(reg)),
+        }
+    }
+
+    pub(crate) fn restore(&mut self, reg: Register, state: State) {
+        self.set(reg, state);
+    }
+
+    // User code no longer needs reg, it can be used for temporaries
+    pub fn release(&mut self, reg: Register) -> Result<(), DynAsmError> {
+        match self.state(reg)? {
+            State::Free | State::Reserved => {
+                self.set(reg, State::Free);
+                Ok(())
+            }
+            _ => Err(DynAsmError::RegisterUnavailable(reg)),
+        }
+    }
+
+    pub fn is_free(&self, reg: Register) -> bool {
+        matches!(self.state(reg), Ok(State::Free))
+    }
+
+    pub(crate) fn alloc(&mut self) -> Option<Register> {
+        let reg = ORDER.into_iter().find(|reg| self.is_free(*reg))?;
+        self.set(reg, State::Temp);
+        Some(reg)
+    }
+
+    // Pick a reserved register that can be pushed to the stack to make room
+    pub(crate) fn spill(&mut self) -> Option<Register> {
+        let reg = ORDER
+            .into_iter()
+            .find(|reg| matches!(self.state(*reg), Ok(State::Reserved)))?;
+        self.set(reg, State::Spilled);
+        self.spills.push(reg);
+        Some(reg)
+    }
+
+    // Live temporaries, spilled registers last in spill order
+    pub(crate) fn temps(&self) -> Vec<Register> {
+        let mut temps: Vec<Register> = ORDER
+            .into_iter()
+            .filter(|reg| matches!(self.state(*reg), Ok(State::Temp)))
+            .collect();
+        temps.extend_from_slice(&self.spills);
+        temps
+    }
+
+    // Returns true when reg was spilled and has to be restored from the stack
+    pub(crate) fn free(&mut self, reg: Register) -> Result<bool, DynAsmError> {
+        match self.state(reg)? {
+            State::Temp => {
+                self.set(reg, State::Free);
+                Ok(false)
+            }
+            State::Spilled if self.spills.last() == Some(&reg) => {
+                self.spills.pop();
+                self.set(reg, State::Reserved);
+                Ok(true)
+            }
+            State::Spilled => Err(DynAsmError::SpillOrder(reg)),
+            _ => Err(DynAsmError::RegisterNotAllocated(reg)),
+        }
+    }
+}
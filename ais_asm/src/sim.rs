@@ -0,0 +1,551 @@
+// Host-side model of the AIS, used to test generated code without VIA C3 hardware.
+//
+// Only the instructions that DynAsm and the stdlib generate are modelled, and the semantics are
+// our best reading of the reference. Segments are flat, descriptor tables sit at the bases in
+// Sim::tables and XLDESC is not modelled. XALUR/XALUIR update EFLAGS and XALU/XALUI
+// don't. Execution stops when it reaches bytes that are not an AIS instruction, or on an XJ to x86.
+
+use crate::ais::{
+    AisError, Cond, Const, Cp2Reg, DpCntl, Function, Instruction, Opcode, Register, Sel, Size,
+    SubFunc, SubOpXalu, XjCond, XjMode,
+};
+use crate::translator::TranslatorState;
+use std::collections::{HashMap, VecDeque};
+
+pub const CF: u32 = 1 << 0;
+pub const PF: u32 = 1 << 2;
+pub const ZF: u32 = 1 << 6;
+pub const SF: u32 = 1 << 7;
+pub const DF: u32 = 1 << 10;
+pub const OF: u32 = 1 << 11;
+
+#[derive(Debug)]
+pub enum SimError {
+    AisError(AisError),
+    Unsupported(Instruction),
+    StepLimit,
+}
+
+impl From<AisError> for SimError {
+    fn from(x: AisError) -> Self {
+        Self::AisError(x)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    X86(u32), // Execution continues in x86 mode at this address
+}
+
+pub trait Io {
+    fn read(&mut self, port: u32, size: Size) -> u32;
+    fn write(&mut self, port: u32, size: Size, value: u32);
+}
+
+// Ports read as zero and writes are ignored
+pub struct NoIo;
+
+impl Io for NoIo {
+    fn read(&mut self, _port: u32, _size: Size) -> u32 {
+        0
+    }
+
+    fn write(&mut self, _port: u32, _size: Size, _value: u32) {}
+}
+
+// Port accesses, with the port and the value read or written
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IoAccess {
+    Read(u32, u32),
+    Write(u32, u32),
+}
+
+// Records every port access made through io
+pub struct IoLog<I: Io> {
+    pub io: I,
+    pub log: Vec<IoAccess>,
+}
+
+impl<I: Io> IoLog<I> {
+    pub fn new(io: I) -> Self {
+        Self {
+            io,
+            log: Vec::new(),
+        }
+    }
+}
+
+impl<I: Io> Io for IoLog<I> {
+    fn read(&mut self, port: u32, size: Size) -> u32 {
+        let value = self.io.read(port, size);
+        self.log.push(IoAccess::Read(port, value));
+        value
+    }
+
+    fn write(&mut self, port: u32, size: Size, value: u32) {
+        self.log.push(IoAccess::Write(port, value));
+        self.io.write(port, size, value);
+    }
+}
+
+pub const UART_MCR_LOOP: u32 = 0x10;
+
+// 16550 UART that is always ready to transmit, transmitted bytes are collected in tx. In loopback
+// mode they are received instead.
+pub struct Uart16550 {
+    pub base: u32,
+    pub tx: Vec<u8>,
+    pub mcr: u32,
+    pub rx: VecDeque<u8>,
+}
+
+impl Uart16550 {
+    pub fn new(base: u32) -> Self {
+        Self {
+            base,
+            tx: Vec::new(),
+            mcr: 0,
+            rx: VecDeque::new(),
+        }
+    }
+}
+
+impl Io for Uart16550 {
+    fn read(&mut self, port: u32, _size: Size) -> u32 {
+        match port.wrapping_sub(self.base) {
+            0 => self.rx.pop_front().unwrap_or(0).into(), // RHR
+            4 => self.mcr,
+            5 => 0x60 | u32::from(!self.rx.is_empty()), // LSR, transmitter empty and data ready
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, port: u32, _size: Size, value: u32) {
+        match port.wrapping_sub(self.base) {
+            0 if self.mcr & UART_MCR_LOOP != 0 => self.rx.push_back(value as u8),
+            0 => self.tx.push(value as u8),
+            4 => self.mcr = value & 0x1F,
+            _ => {}
+        }
+    }
+}
+
+fn size_bytes(size: Size) -> Option<u32> {
+    match size {
+        Size::Bits8L | Size::Bits8H => Some(1),
+        Size::Bits16 => Some(2),
+        Size::Bits32 => Some(4),
+        _ => None,
+    }
+}
+
+// Result of an I type instruction
+pub fn i_type_value(opcode: Opcode, rs: u32, imm: u16) -> Option<u32> {
+    let imm = u32::from(imm);
+
+    Some(match opcode {
+        Opcode::ORI => rs | imm,
+        Opcode::ORIU => rs | imm << 16,
+        Opcode::ANDI => rs & imm,
+        Opcode::ANDIL => rs & (0xFFFF_0000 | imm),
+        Opcode::ANDIU => rs & (imm << 16 | 0xFFFF),
+        Opcode::XORI => rs ^ imm,
+        Opcode::XORIU => rs ^ imm << 16,
+        Opcode::ADDI => rs.wrapping_add(imm as u16 as i16 as u32),
+        _ => return None,
+    })
+}
+
+fn parity(x: u32) -> bool {
+    (x as u8).count_ones().is_multiple_of(2)
+}
+
+pub struct Sim<I: Io> {
+    pub regs: [u32; 32],
+    pub eflags: u32,
+    pub cp2: [u32; 32],
+    pub tables: [Option<u32>; 4], // Bases of the GDT, LDT, IDT and TSS, None when not set up
+    pub translator: TranslatorState,
+    pub io: I,
+    memory: HashMap<u32, u8>,
+}
+
+impl<I: Io> Sim<I> {
+    pub fn new(io: I) -> Self {
+        Self {
+            regs: [0; 32],
+            eflags: 0x2,
+            cp2: [0; 32],
+            tables: [None; 4],
+            translator: TranslatorState::default(),
+            io,
+            memory: HashMap::new(),
+        }
+    }
+
+    pub fn reg(&self, reg: Register) -> u32 {
+        self.regs[reg.0 as usize]
+    }
+
+    pub fn set_reg(&mut self, reg: Register, value: u32) {
+        if reg != Register::R0 {
+            self.regs[reg.0 as usize] = value;
+        }
+    }
+
+    pub fn load(&mut self, addr: u32, bytes: &[u8]) {
+        for (i, b) in bytes.iter().enumerate() {
+            self.memory.insert(addr.wrapping_add(i as u32), *b);
+        }
+    }
+
+    pub fn read_bytes(&self, addr: u32, len: usize) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| *self.memory.get(&addr.wrapping_add(i)).unwrap_or(&0))
+            .collect()
+    }
+
+    pub fn read(&self, addr: u32, len: u32) -> u32 {
+        let bytes = self.read_bytes(addr, len as usize);
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |acc, b| acc << 8 | u32::from(*b))
+    }
+
+    pub fn write(&mut self, addr: u32, len: u32, value: u32) {
+        let bytes = value.to_le_bytes();
+        self.load(addr, &bytes[..len as usize]);
+    }
+
+    pub fn cond(&self, cond: Cond) -> bool {
+        let flag = |f| self.eflags & f != 0;
+        let (cf, zf, sf, of, pf) = (flag(CF), flag(ZF), flag(SF), flag(OF), flag(PF));
+
+        let holds = match cond as u8 >> 1 {
+            0 => of,
+            1 => cf,
+            2 => zf,
+            3 => cf || zf,
+            4 => sf,
+            5 => pf,
+            6 => sf != of,
+            _ => zf || sf != of,
+        };
+
+        // Odd conditions are the negation of the even ones
+        holds ^ (cond as u8 & 1 == 1)
+    }
+
+    fn set_flags(&mut self, result: u32, carry: bool, overflow: bool) {
+        let mut eflags = self.eflags & !(CF | PF | ZF | SF | OF);
+        if carry {
+            eflags |= CF;
+        }
+        if parity(result) {
+            eflags |= PF;
+        }
+        if result == 0 {
+            eflags |= ZF;
+        }
+        if result & 0x8000_0000 != 0 {
+            eflags |= SF;
+        }
+        if overflow {
+            eflags |= OF;
+        }
+        self.eflags = eflags;
+    }
+
+    // Special offsets use the translator state, with DF taken from EFLAGS
+    fn offset(&self, instr: &Instruction) -> Result<u32, SimError> {
+        let state = TranslatorState {
+            df: self.eflags & DF != 0,
+            ..self.translator
+        };
+        instr
+            .offset
+            .and_then(|offset| state.resolve(offset))
+            .ok_or(SimError::Unsupported(*instr))
+    }
+
+    fn i_type(&mut self, instr: &Instruction) -> Result<(), SimError> {
+        let rs = self.reg(instr.rs.unwrap());
+        let value = i_type_value(instr.opcode, rs, instr.imm.unwrap())
+            .ok_or(SimError::Unsupported(*instr))?;
+
+        self.set_reg(instr.rt.unwrap(), value);
+        Ok(())
+    }
+
+    fn xalu(&mut self, instr: &Instruction) -> Result<(), SimError> {
+        let (sub_op, dp_cntl) = match instr.function {
+            Some(Function::Xalu(sub_op, dp_cntl)) => (sub_op, dp_cntl),
+            _ => return Err(SimError::Unsupported(*instr)),
+        };
+        if !matches!(dp_cntl, DpCntl::Word) {
+            return Err(SimError::Unsupported(*instr));
+        }
+
+        let a = self.reg(instr.rs.unwrap());
+        let b = match instr.opcode {
+            Opcode::XALU | Opcode::XALUR => self.reg(instr.rt.unwrap()),
+            _ => match instr.constant {
+                Some(Const::Number(x)) => x as i32 as u32,
+                _ => return Err(SimError::Unsupported(*instr)),
+            },
+        };
+        let carry_in = self.eflags & CF != 0;
+
+        let (result, carry, overflow) = match sub_op {
+            SubOpXalu::ADD | SubOpXalu::ADC => {
+                let c = matches!(sub_op, SubOpXalu::ADC) && carry_in;
+                let wide = u64::from(a) + u64::from(b) + u64::from(c);
+                let r = wide as u32;
+                (r, wide > 0xFFFF_FFFF, (!(a ^ b) & (a ^ r)) >> 31 == 1)
+            }
+            SubOpXalu::SUB | SubOpXalu::SBB => {
+                let c = matches!(sub_op, SubOpXalu::SBB) && carry_in;
+                let r = a.wrapping_sub(b).wrapping_sub(u32::from(c));
+                let borrow = u64::from(a) < u64::from(b) + u64::from(c);
+                (r, borrow, ((a ^ b) & (a ^ r)) >> 31 == 1)
+            }
+            SubOpXalu::INC => (a.wrapping_add(1), carry_in, a == 0x7FFF_FFFF),
+            SubOpXalu::DEC => (a.wrapping_sub(1), carry_in, a == 0x8000_0000),
+            SubOpXalu::AND => (a & b, false, false),
+            SubOpXalu::OR => (a | b, false, false),
+            SubOpXalu::XOR => (a ^ b, false, false),
+            SubOpXalu::NOR => (!(a | b), false, false),
+            SubOpXalu::SHL => (a.wrapping_shl(b & 31), false, false),
+            SubOpXalu::SHR => (a.wrapping_shr(b & 31), false, false),
+            SubOpXalu::SAR => ((a as i32).wrapping_shr(b & 31) as u32, false, false),
+            SubOpXalu::ROL => (a.rotate_left(b & 31), false, false),
+            SubOpXalu::ROR => (a.rotate_right(b & 31), false, false),
+            SubOpXalu::CTC2 => {
+                self.ctc2(instr.rd.unwrap().0, a);
+                return Ok(());
+            }
+            _ => return Err(SimError::Unsupported(*instr)),
+        };
+
+        self.set_reg(instr.rd.unwrap(), result);
+        if matches!(instr.opcode, Opcode::XALUR | Opcode::XALUIR) {
+            self.set_flags(result, carry, overflow);
+        }
+
+        Ok(())
+    }
+
+    fn cfc2(&self, index: u8) -> u32 {
+        match index {
+            x if x == Cp2Reg::EFLAGS.0 => self.eflags,
+            x => self.cp2[x as usize],
+        }
+    }
+
+    fn ctc2(&mut self, index: u8, value: u32) {
+        match index {
+            x if x == Cp2Reg::EFLAGS.0 => self.eflags = value,
+            x => self.cp2[x as usize] = value,
+        }
+    }
+
+    // Segments are flat, descriptor tables are flat segments at the bases in tables
+    fn seg_base(&self, instr: &Instruction) -> Result<u32, SimError> {
+        let sel = match instr.function {
+            Some(Function::Xls(_, _, _, sel)) => sel as usize,
+            _ => return Ok(0),
+        };
+        match sel.checked_sub(Sel::GDT as usize) {
+            Some(table) if table < self.tables.len() => {
+                self.tables[table].ok_or(SimError::Unsupported(*instr))
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn xls_size(&self, instr: &Instruction) -> Result<(Size, u32), SimError> {
+        let size = match instr.function {
+            Some(Function::Xls(_, _, size, _)) | Some(Function::Xio(_, _, size, _)) => size,
+            _ => return Err(SimError::Unsupported(*instr)),
+        };
+        let bytes = size_bytes(size).ok_or(SimError::Unsupported(*instr))?;
+        Ok((size, bytes))
+    }
+
+    // Merge a narrow value into a register, like x86 does for AL/AH/AX
+    fn merge(&self, reg: Register, size: Size, value: u32) -> u32 {
+        let old = self.reg(reg);
+        match size {
+            Size::Bits8L => old & !0xFF | value & 0xFF,
+            Size::Bits8H => old & !0xFF00 | (value & 0xFF) << 8,
+            Size::Bits16 => old & !0xFFFF | value & 0xFFFF,
+            _ => value,
+        }
+    }
+
+    // Execute the instruction at ip, returns the next ip or where execution left AIS mode
+    pub fn step(&mut self, ip: u32) -> Result<Result<u32, Stop>, SimError> {
+        let bytes = self.read_bytes(ip, 6);
+        let (instr, len) = match Instruction::decode(&bytes) {
+            Ok(x) => x,
+            Err(AisError::DecodeHeader) => return Ok(Err(Stop::X86(ip))),
+            Err(e) => return Err(e.into()),
+        };
+        let next = ip + len as u32;
+
+        if instr.is_i_type() {
+            self.i_type(&instr)?;
+            return Ok(Ok(next));
+        }
+
+        match instr.opcode {
+            Opcode::XALU | Opcode::XALUR | Opcode::XALUI | Opcode::XALUIR => self.xalu(&instr)?,
+            Opcode::XJ => {
+                let target = self.reg(instr.rt.unwrap());
+                let (cond, mode) = match instr.function {
+                    Some(Function::Xj(_, cond, mode)) => (cond, mode),
+                    _ => return Err(SimError::Unsupported(instr)),
+                };
+                let taken = match cond {
+                    XjCond::Always => true,
+                    XjCond::If(cond) => self.cond(cond),
+                    XjCond::Raw(_) => return Err(SimError::Unsupported(instr)),
+                };
+                return Ok(match (taken, mode) {
+                    (false, _) => Ok(next),
+                    (true, XjMode::AIS) => Ok(target),
+                    (true, XjMode::X86) => Err(Stop::X86(target)),
+                });
+            }
+            Opcode::XPUSH | Opcode::XPUSHIP => {
+                let (_, len) = self.xls_size(&instr)?;
+                let base = instr.rt.unwrap();
+                let addr = self.reg(base).wrapping_add(self.offset(&instr)?);
+                let value = match instr.opcode {
+                    Opcode::XPUSHIP => next,
+                    _ => self.reg(instr.rs.unwrap()),
+                };
+                self.set_reg(base, addr);
+                self.write(addr, len, value);
+            }
+            Opcode::XPOP => {
+                let (size, len) = self.xls_size(&instr)?;
+                let base = instr.rt.unwrap();
+                let addr = self.reg(base);
+                let value = self.read(addr, len);
+                let dst = instr.rs.unwrap();
+                self.set_reg(base, addr.wrapping_add(self.offset(&instr)?));
+                self.set_reg(dst, self.merge(dst, size, value));
+            }
+            Opcode::XL => {
+                let (size, len) = self.xls_size(&instr)?;
+                let addr = self
+                    .seg_base(&instr)?
+                    .wrapping_add(self.reg(instr.rt.unwrap()))
+                    .wrapping_add(self.offset(&instr)?);
+                let dst = instr.rs.unwrap();
+                let value = self.read(addr, len);
+                self.set_reg(dst, self.merge(dst, size, value));
+            }
+            Opcode::XS => {
+                let (size, len) = self.xls_size(&instr)?;
+                let addr = self
+                    .seg_base(&instr)?
+                    .wrapping_add(self.reg(instr.rt.unwrap()))
+                    .wrapping_add(self.offset(&instr)?);
+                let value = match size {
+                    Size::Bits8H => self.reg(instr.rs.unwrap()) >> 8,
+                    _ => self.reg(instr.rs.unwrap()),
+                };
+                self.write(addr, len, value);
+            }
+            Opcode::XIOR | Opcode::XIOW => {
+                let (size, _) = self.xls_size(&instr)?;
+                let port = self
+                    .reg(instr.rt.unwrap())
+                    .wrapping_add(self.offset(&instr)?);
+                let reg = instr.rs.unwrap();
+                if instr.opcode == Opcode::XIOR {
+                    let value = self.io.read(port, size);
+                    self.set_reg(reg, self.merge(reg, size, value));
+                } else {
+                    self.io.write(port, size, self.reg(reg));
+                }
+            }
+            Opcode::XLEAD => {
+                let addr = self
+                    .reg(instr.rt.unwrap())
+                    .wrapping_add(self.offset(&instr)?);
+                self.set_reg(instr.rs.unwrap(), addr);
+            }
+            Opcode::XMISC => match instr.function {
+                Some(Function::Xmisc(SubFunc::CFC2, _)) => {
+                    let value = self.cfc2(instr.rd.unwrap().0);
+                    self.set_reg(instr.rt.unwrap(), value);
+                }
+                _ => return Err(SimError::Unsupported(instr)),
+            },
+            _ => return Err(SimError::Unsupported(instr)),
+        }
+
+        Ok(Ok(next))
+    }
+
+    pub fn run(&mut self, mut ip: u32, max_steps: usize) -> Result<Stop, SimError> {
+        for _ in 0..max_steps {
+            match self.step(ip)? {
+                Ok(next) => ip = next,
+                Err(stop) => return Ok(stop),
+            }
+        }
+        Err(SimError::StepLimit)
+    }
+}
+
+#[test]
+fn conditions_follow_x86() {
+    let mut sim = Sim::new(NoIo);
+    sim.eflags = ZF;
+    assert!(sim.cond(Cond::Z));
+    assert!(!sim.cond(Cond::NZ));
+    assert!(sim.cond(Cond::BE));
+    assert!(sim.cond(Cond::LE));
+    assert!(!sim.cond(Cond::G));
+
+    sim.eflags = SF;
+    assert!(sim.cond(Cond::L));
+    assert!(!sim.cond(Cond::GE));
+}
+
+#[test]
+fn eflags_through_cp2() {
+    use crate::dynasm::DynAsm;
+
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_load(Register::EAX, ZF | 0x2).unwrap();
+    asm.gen_write_eflags(Register::EAX).unwrap();
+    asm.gen_read_eflags(Register::EBX).unwrap();
+    asm.gen(crate::asm::jx86(Register::R0)).unwrap();
+
+    let mut sim = Sim::new(NoIo);
+    sim.load(0x1000, asm.memory());
+    sim.run(0x1000, 10).unwrap();
+    assert!(sim.cond(Cond::Z));
+    assert_eq!(sim.reg(Register::EBX), ZF | 0x2);
+}
+
+#[test]
+fn tsc_through_cp2() {
+    use crate::dynasm::DynAsm;
+
+    let mut asm = DynAsm::new(0x1000);
+    asm.gen_read_tsc_low(Register::EBX).unwrap();
+    asm.gen(crate::asm::jx86(Register::R0)).unwrap();
+
+    let mut sim = Sim::new(NoIo);
+    sim.cp2[Cp2Reg::TSC_L.0 as usize] = 0x1234_5678;
+    sim.load(0x1000, asm.memory());
+    sim.run(0x1000, 10).unwrap();
+    assert_eq!(sim.reg(Register::EBX), 0x1234_5678);
+}
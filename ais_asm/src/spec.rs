@@ -0,0 +1,462 @@
+// Declarative description of the instruction formats.
+//
+// Fields are lists of bit ranges, most significant part first, so split fields like the XLS size
+// are a single entry. encode32 and decode32 pack and unpack through these tables, disasm takes its
+// mnemonics, operand order and value names from them and the asm builders their operand fields.
+//
+//        31:26    25:21    20:16   15:11    10:0
+// I:     Opcode | RS     | RT    | Immediate
+// XALU:  Opcode | RS     | RT    | RD     | Function
+// XALUI: Opcode | RS     | Const | RD     | Function
+// XMISC: Opcode | RS     | RT    | RD     | Function
+// XLS:   Opcode | Offset | Base  | RS     | Function
+// XJ:    Opcode |        | Base  |        | Function
+
+use crate::ais::{Field, Opcode, XJ_COND_ENABLE, XJ_TTTN_ALWAYS};
+
+pub type Bits = &'static [(u32, u32)];
+
+pub const OPCODE: Bits = &[(31, 26)];
+
+pub fn width(bits: Bits) -> u32 {
+    bits.iter().map(|&(high, low)| high - low + 1).sum()
+}
+
+pub fn extract(word: u32, bits: Bits) -> u32 {
+    bits.iter().fold(0, |acc, &(high, low)| {
+        acc << (high - low + 1) | crate::bits(word, high, low)
+    })
+}
+
+// None when value does not fit
+pub fn insert(value: u32, bits: Bits) -> Option<u32> {
+    if value >> width(bits) != 0 {
+        return None;
+    }
+
+    let mut value = value;
+    let mut word = 0;
+    for &(high, low) in bits.iter().rev() {
+        let len = high - low + 1;
+        word |= (value & ((1 << len) - 1)) << low;
+        value >>= len;
+    }
+    Some(word)
+}
+
+// Values a field can take, names come from the notes and the reference
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Kind {
+    Register,
+    Immediate,
+    Const,
+    Offset,
+    SubOpXalu,
+    DpCntl,
+    SubOp,
+    SubOpXio,
+    AddrSize,
+    Size,
+    Sel,
+    XjSize,
+    XjCond,
+    XjMode,
+    SubFunc,
+    Number,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FieldSpec {
+    pub name: &'static str,
+    pub field: Field,
+    pub kind: Kind,
+    pub bits: Bits,
+}
+
+const fn field(name: &'static str, field: Field, kind: Kind, bits: Bits) -> FieldSpec {
+    FieldSpec {
+        name,
+        field,
+        kind,
+        bits,
+    }
+}
+
+// Operands are in assembly order, function fields in the order of the Function variant
+#[derive(Debug)]
+pub struct FormatSpec {
+    pub name: &'static str,
+    pub operands: &'static [FieldSpec],
+    pub function: &'static [FieldSpec],
+}
+
+const RS: FieldSpec = field("rs", Field::RS, Kind::Register, &[(25, 21)]);
+const RT: FieldSpec = field("rt", Field::RT, Kind::Register, &[(20, 16)]);
+const RD: FieldSpec = field("rd", Field::RD, Kind::Register, &[(15, 11)]);
+const XLS_RS: FieldSpec = field("rs", Field::RS, Kind::Register, &[(15, 11)]);
+const BASE: FieldSpec = field("base", Field::RT, Kind::Register, &[(20, 16)]);
+const OFFSET: FieldSpec = field("offset", Field::Offset, Kind::Offset, &[(25, 21)]);
+
+// There is no room for the fourth size bit in XLS, it overlaps with Sel
+const XLS_FUNCTION: &[FieldSpec] = &[
+    field("sub_op", Field::Function, Kind::SubOp, &[(10, 9)]),
+    field(
+        "addr_size",
+        Field::Function,
+        Kind::AddrSize,
+        &[(8, 8), (0, 0)],
+    ),
+    field("size", Field::Function, Kind::Size, &[(7, 6), (1, 1)]),
+    field("sel", Field::Function, Kind::Sel, &[(5, 2)]),
+];
+
+pub const I: FormatSpec = FormatSpec {
+    name: "I",
+    operands: &[
+        RT,
+        RS,
+        field("imm", Field::Immediate, Kind::Immediate, &[(15, 0)]),
+    ],
+    function: &[],
+};
+
+pub const XALU: FormatSpec = FormatSpec {
+    name: "XALU",
+    operands: &[RD, RS, RT],
+    function: &[
+        field("sub_op", Field::Function, Kind::SubOpXalu, &[(4, 0)]),
+        field("dp_cntl", Field::Function, Kind::DpCntl, &[(7, 5)]),
+    ],
+};
+
+pub const XALUI: FormatSpec = FormatSpec {
+    name: "XALUI",
+    operands: &[
+        RD,
+        RS,
+        field("const", Field::Const, Kind::Const, &[(20, 16)]),
+    ],
+    function: XALU.function,
+};
+
+pub const XMISC: FormatSpec = FormatSpec {
+    name: "XMISC",
+    operands: &[RS, RT, RD],
+    function: &[
+        field("sub_func", Field::Function, Kind::SubFunc, &[(10, 6)]),
+        field("other", Field::Function, Kind::Number, &[(5, 0)]),
+    ],
+};
+
+pub const XLS: FormatSpec = FormatSpec {
+    name: "XLS",
+    operands: &[XLS_RS, BASE, OFFSET],
+    function: XLS_FUNCTION,
+};
+
+// Same layout as XLS, rs is the value and base the port. Port I/O has not been seen working on
+// hardware, so XIOR and XIOW only encode with the unconfirmed feature.
+pub const XIO: FormatSpec = FormatSpec {
+    name: "XIO",
+    operands: &[XLS_RS, BASE, OFFSET],
+    function: &[
+        field("sub_op", Field::Function, Kind::SubOpXio, &[(10, 9)]),
+        XLS_FUNCTION[1],
+        XLS_FUNCTION[2],
+        XLS_FUNCTION[3],
+    ],
+};
+
+// The condition is the enable in 15:11 followed by tttn in 5:2
+pub const XJ: FormatSpec = FormatSpec {
+    name: "XJ",
+    operands: &[BASE],
+    function: &[
+        field("size", Field::Function, Kind::XjSize, &[(7, 6)]),
+        field("cond", Field::Function, Kind::XjCond, &[(15, 11), (5, 2)]),
+        field("mode", Field::Function, Kind::XjMode, &[(1, 0)]),
+    ],
+};
+
+pub const XLEA: FormatSpec = FormatSpec {
+    name: "XLEA",
+    operands: &[XLS_RS, BASE, OFFSET],
+    function: &[
+        field(
+            "addr_size",
+            Field::Function,
+            Kind::AddrSize,
+            &[(8, 8), (0, 0)],
+        ),
+        field(
+            "size",
+            Field::Function,
+            Kind::Size,
+            &[(2, 2), (7, 6), (1, 1)],
+        ),
+    ],
+};
+
+#[derive(Debug)]
+pub struct OpcodeSpec {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub format: Option<&'static FormatSpec>, // None when the layout is not known
+}
+
+const fn op(opcode: Opcode, mnemonic: &'static str, format: &'static FormatSpec) -> OpcodeSpec {
+    OpcodeSpec {
+        opcode,
+        mnemonic,
+        format: Some(format),
+    }
+}
+
+// XLEAI has three registers but only two register fields are known. XPOPBR and XPUSHI have not
+// been looked at.
+pub const OPCODES: &[OpcodeSpec] = &[
+    op(Opcode::XJ, "xj", &XJ),
+    op(Opcode::ORIU, "oriu", &I),
+    op(Opcode::ADDI, "addi", &I),
+    op(Opcode::ANDIU, "andiu", &I),
+    op(Opcode::ANDIL, "andil", &I),
+    op(Opcode::ANDI, "andi", &I),
+    op(Opcode::ORI, "ori", &I),
+    op(Opcode::XORI, "xori", &I),
+    op(Opcode::XORIU, "xoriu", &I),
+    op(Opcode::XALU, "xalu", &XALU),
+    op(Opcode::XALUI, "xalui", &XALUI),
+    op(Opcode::XALUR, "xalur", &XALU),
+    op(Opcode::XALUIR, "xaluir", &XALUI),
+    op(Opcode::XMISC, "xmisc", &XMISC),
+    OpcodeSpec {
+        opcode: Opcode::XLEAI,
+        mnemonic: "xleai",
+        format: None,
+    },
+    op(Opcode::XLEAD, "xlead", &XLEA),
+    op(Opcode::XL, "xl", &XLS),
+    op(Opcode::XL2, "xl2", &XLS),
+    op(Opcode::XL3, "xl3", &XLS),
+    op(Opcode::XLBI, "xlbi", &XLS),
+    op(Opcode::XLDESC, "xldesc", &XLS),
+    op(Opcode::XIOR, "xior", &XIO),
+    OpcodeSpec {
+        opcode: Opcode::XPOPBR,
+        mnemonic: "xpopbr",
+        format: None,
+    },
+    op(Opcode::XPOP, "xpop", &XLS),
+    op(Opcode::XS, "xs", &XLS),
+    op(Opcode::XS2, "xs2", &XLS),
+    OpcodeSpec {
+        opcode: Opcode::XPUSHI,
+        mnemonic: "xpushi",
+        format: None,
+    },
+    op(Opcode::XSI, "xsi", &XLS),
+    op(Opcode::XPUSHIP, "xpuship", &XLS),
+    op(Opcode::XIOW, "xiow", &XIO),
+    op(Opcode::XSU, "xsu", &XLS),
+    op(Opcode::XPUSH, "xpush", &XLS),
+];
+
+pub fn opcode(opcode: Opcode) -> &'static OpcodeSpec {
+    OPCODES.iter().find(|x| x.opcode == opcode).unwrap()
+}
+
+pub fn mnemonic(mnemonic: &str) -> Option<&'static OpcodeSpec> {
+    OPCODES.iter().find(|x| x.mnemonic == mnemonic)
+}
+
+pub fn format(op: Opcode) -> Option<&'static FormatSpec> {
+    opcode(op).format
+}
+
+const REGISTERS: [&str; 32] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "cs", "ss", "ds", "fs", "gs", "r14",
+    "r15", "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r24", "r25", "r26", "r27",
+    "r28", "r29", "r30", "r31",
+];
+
+const COND: u32 = XJ_COND_ENABLE << 4;
+
+// Function values without a name are written as numbers
+fn names(kind: Kind) -> &'static [(&'static str, u32)] {
+    match kind {
+        Kind::SubOpXalu => &[
+            ("shl", 0o00),
+            ("shr", 0o02),
+            ("sar", 0o03),
+            ("rol", 0o04),
+            ("ror", 0o05),
+            ("rcl", 0o06),
+            ("rcr", 0o07),
+            ("inc", 0o10),
+            ("cmps", 0o11),
+            ("dec", 0o12),
+            ("imul", 0o14),
+            ("mul", 0o15),
+            ("idiv", 0o16),
+            ("add", 0o20),
+            ("adc", 0o21),
+            ("sub", 0o22),
+            ("sbb", 0o23),
+            ("and", 0o24),
+            ("or", 0o25),
+            ("xor", 0o26),
+            ("nor", 0o27),
+            ("ctc2", 0o31),
+            ("setcc", 0o35),
+            ("mflou", 0o36),
+            ("mfloi", 0o37),
+        ],
+        Kind::DpCntl => &[
+            ("word", 0b000),
+            ("short", 0b001),
+            ("ll", 0b010),
+            ("hl", 0b011),
+            ("lh", 0b100),
+            ("hh", 0b101),
+        ],
+        Kind::SubOpXio => &[("norm", 0)],
+        Kind::AddrSize => &[("as", 0b00), ("sas", 0b01), ("a16", 0b10), ("a32", 0b11)],
+        Kind::Size => &[
+            ("b16", 0b000),
+            ("b8l", 0b001),
+            ("b32", 0b010),
+            ("b8h", 0b011),
+            ("as", 0b100),
+            ("b64", 0b101),
+            ("os", 0b110),
+            ("ind", 0b111),
+            ("sas", 0b1000),
+        ],
+        Kind::Sel => &[
+            ("es", 0b0000),
+            ("cs", 0b0001),
+            ("ss", 0b0010),
+            ("ds", 0b0011),
+            ("fs", 0b0100),
+            ("gs", 0b0101),
+            ("gdt", 0b0110),
+            ("ldt", 0b0111),
+            ("idt", 0b1000),
+            ("tss", 0b1001),
+            ("flat", 0b1010),
+            ("t0", 0b1011),
+            ("isel", 0b1111),
+        ],
+        Kind::XjSize => &[("b16", 0b00), ("b32", 0b01), ("as", 0b10), ("os", 0b11)],
+        Kind::XjCond => &[
+            ("always", XJ_TTTN_ALWAYS),
+            ("o", COND),
+            ("no", COND | 0b0001),
+            ("c", COND | 0b0010),
+            ("nc", COND | 0b0011),
+            ("z", COND | 0b0100),
+            ("nz", COND | 0b0101),
+            ("be", COND | 0b0110),
+            ("a", COND | 0b0111),
+            ("s", COND | 0b1000),
+            ("ns", COND | 0b1001),
+            ("p", COND | 0b1010),
+            ("np", COND | 0b1011),
+            ("l", COND | 0b1100),
+            ("ge", COND | 0b1101),
+            ("le", COND | 0b1110),
+            ("g", COND | 0b1111),
+        ],
+        Kind::XjMode => &[("ais", 0b00), ("x86", 0b11)],
+        Kind::SubFunc => &[("cfc2", 0o37)],
+        _ => &[],
+    }
+}
+
+pub fn name(kind: Kind, value: u32) -> Option<&'static str> {
+    match kind {
+        Kind::Register => REGISTERS.get(value as usize).copied(),
+        _ => names(kind).iter().find(|x| x.1 == value).map(|x| x.0),
+    }
+}
+
+pub fn value(kind: Kind, name: &str) -> Option<u32> {
+    match kind {
+        Kind::Register => REGISTERS.iter().position(|&x| x == name).map(|x| x as u32),
+        _ => names(kind).iter().find(|x| x.0 == name).map(|x| x.1),
+    }
+}
+
+#[test]
+fn fields_do_not_overlap() {
+    for op in OPCODES {
+        let Some(format) = op.format else { continue };
+        let mut used = insert(op.opcode as u32, OPCODE).unwrap();
+
+        for field in format.operands.iter().chain(format.function) {
+            let mask = insert((1 << width(field.bits)) - 1, field.bits).unwrap();
+            assert_eq!(used & mask, 0, "{} {}", op.mnemonic, field.name);
+            used |= mask;
+        }
+    }
+
+    // Every opcode appears once
+    for op in OPCODES {
+        assert_eq!(opcode(op.opcode).mnemonic, op.mnemonic);
+        assert_eq!(mnemonic(op.mnemonic).unwrap().opcode, op.opcode);
+    }
+}
+
+#[test]
+fn split_fields_round_trip() {
+    let size = XLEA.function[1].bits;
+    let word = insert(0b1011, size).unwrap();
+    assert_eq!(word, 1 << 2 | 0b01 << 6 | 1 << 1);
+    assert_eq!(extract(word, size), 0b1011);
+    assert_eq!(insert(0b1000, XLS.function[2].bits), None);
+}
+
+#[test]
+fn names_match_enums() {
+    use crate::ais::{
+        AddrSize, Cond, DpCntl, Sel, Size, SubFunc, SubOpXalu, SubOpXio, XjMode, XjSize,
+    };
+    use num::FromPrimitive;
+
+    // A value has a name exactly when its enum has a variant for it
+    fn check<T: FromPrimitive>(kind: Kind) {
+        assert!(names(kind).iter().all(|x| x.1 < 1 << 8), "{:?}", kind);
+        for value in 0..1 << 8 {
+            let named = name(kind, value).is_some();
+            assert_eq!(T::from_u32(value).is_some(), named, "{:?} {}", kind, value);
+        }
+    }
+
+    check::<SubOpXalu>(Kind::SubOpXalu);
+    check::<DpCntl>(Kind::DpCntl);
+    check::<AddrSize>(Kind::AddrSize);
+    check::<Size>(Kind::Size);
+    check::<Sel>(Kind::Sel);
+    check::<XjSize>(Kind::XjSize);
+    check::<XjMode>(Kind::XjMode);
+    check::<SubFunc>(Kind::SubFunc);
+
+    for value in 0..4 {
+        let norm = matches!(SubOpXio::try_from(value), Ok(SubOpXio::Norm));
+        assert_eq!(name(Kind::SubOpXio, value.into()).is_some(), norm);
+    }
+
+    // Conditions are named as the enable bit over the tttn of Cond, plus always
+    assert_eq!(name(Kind::XjCond, XJ_TTTN_ALWAYS), Some("always"));
+    for value in (0..1 << 9).filter(|&x| x != XJ_TTTN_ALWAYS) {
+        let cond = (value & !0xF == COND)
+            .then(|| Cond::from_u32(value & 0xF))
+            .flatten();
+        assert_eq!(
+            name(Kind::XjCond, value).is_some(),
+            cond.is_some(),
+            "{}",
+            value
+        );
+    }
+}
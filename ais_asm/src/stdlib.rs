@@ -0,0 +1,423 @@
+// Library of common routines.
+//
+// The gen_* routines are placed at the current location, keep them out of the execution path and
+// call them with DynAsm::gen_call(). Arguments are passed in x86 registers, each routine lists the
+// registers it clobbers. A call also clobbers the DynAsm scratch registers R4..R7.
+
+use crate::ais::{Cond, Const, Offset, Register, Size};
+use crate::asm;
+use crate::dynasm::{DynAsm, DynAsmError, Sym};
+
+const EAX: Register = Register::EAX;
+const ECX: Register = Register::ECX;
+const EDX: Register = Register::EDX;
+const EBX: Register = Register::EBX;
+const ESI: Register = Register::ESI;
+const EDI: Register = Register::EDI;
+
+pub const COM1: u16 = 0x3F8;
+const UART_THR: u16 = 0;
+const UART_MCR: u16 = 4;
+const UART_LSR: u16 = 5;
+const UART_LSR_READY: u32 = 0x01;
+const UART_LSR_EMPTY: u32 = 0x20;
+const UART_MCR_LOOP: u32 = 0x10;
+const LOOPBACK_TIMEOUT: u32 = 0x1_0000;
+
+// Copy ECX bytes from ESI to EDI
+// Clobbers: EAX, ECX, ESI, EDI
+pub fn gen_memcpy(asm: &mut DynAsm) -> Result<Sym, DynAsmError> {
+    let sym = asm.new_sym_here();
+
+    asm.while_nonzero(ECX, |asm| {
+        asm.gen_load(EAX, 0)?;
+        asm.gen(asm::pop(Size::Bits8L, EAX, ESI, Offset::Number(1)))?;
+        asm.gen(asm::push(Size::Bits8L, EAX, EDI, Offset::Number(0)))?;
+        asm.gen(asm::addi(EDI, EDI, Const::Number(1)))?;
+        asm.gen(asm::subi(ECX, ECX, Const::Number(1)))
+    })?;
+
+    asm.gen_ret()?;
+    Ok(sym)
+}
+
+// Fill ECX bytes at EDI with the low byte of EAX
+// Clobbers: ECX, EDI
+pub fn gen_memset(asm: &mut DynAsm) -> Result<Sym, DynAsmError> {
+    let sym = asm.new_sym_here();
+
+    asm.while_nonzero(ECX, |asm| {
+        asm.gen(asm::push(Size::Bits8L, EAX, EDI, Offset::Number(0)))?;
+        asm.gen(asm::addi(EDI, EDI, Const::Number(1)))?;
+        asm.gen(asm::subi(ECX, ECX, Const::Number(1)))
+    })?;
+
+    asm.gen_ret()?;
+    Ok(sym)
+}
+
+// Length of the zero terminated string at ESI, returned in EAX
+// Clobbers: EAX, ECX, ESI
+pub fn gen_strlen(asm: &mut DynAsm) -> Result<Sym, DynAsmError> {
+    let sym = asm.new_sym_here();
+    let done = asm.new_sym();
+
+    asm.gen_load(EAX, 0)?;
+    let top = asm.new_sym_here();
+    asm.gen_load(ECX, 0)?;
+    asm.gen(asm::pop(Size::Bits8L, ECX, ESI, Offset::Number(1)))?;
+    asm.gen_branch_zero(ECX, done)?;
+    asm.gen(asm::addi(EAX, EAX, Const::Number(1)))?;
+    asm.gen_jump(top)?;
+
+    asm.set_sym_here(done)?;
+    asm.gen_ret()?;
+    Ok(sym)
+}
+
+// Write the low byte of ECX to the 16550 UART at base, waits until the transmitter is empty
+// Clobbers: EAX, EDX
+pub fn gen_putc(asm: &mut DynAsm, base: u16) -> Result<Sym, DynAsmError> {
+    let sym = asm.new_sym_here();
+
+    asm.gen_load(EDX, (base + UART_LSR).into())?;
+    let wait = asm.new_sym_here();
+    asm.gen_load(EAX, 0)?;
+    asm.gen(asm::ior(Size::Bits8L, EDX, EAX))?;
+    let tmp = asm.scratch()?;
+    asm.gen_load(tmp, UART_LSR_EMPTY)?;
+    asm.gen(asm::and(EAX, EAX, tmp))?;
+    asm.free_temp(tmp)?;
+    asm.gen_branch(Cond::Z, wait)?;
+
+    asm.gen_load(EDX, (base + UART_THR).into())?;
+    asm.gen(asm::iow(Size::Bits8L, EDX, ECX))?;
+
+    asm.gen_ret()?;
+    Ok(sym)
+}
+
+// Send the low byte of ECX through the 16550 UART at base in loopback mode, checks XIOR and XIOW
+// without anything connected. The received byte is returned in EAX, 0xFFFFFFFF when nothing arrived.
+// Clobbers: EAX, EDX, ESI
+pub fn gen_uart_loopback(asm: &mut DynAsm, base: u16) -> Result<Sym, DynAsmError> {
+    let sym = asm.new_sym_here();
+    let timeout = asm.new_sym();
+    let done = asm.new_sym();
+
+    asm.gen_load(EDX, (base + UART_MCR).into())?;
+    asm.gen_load(EAX, 0)?;
+    asm.gen(asm::ior8(EDX, EAX))?;
+    asm.gen(asm::pushsp(Size::Bits32, EAX))?;
+    let tmp = asm.scratch()?;
+    asm.gen_load(tmp, UART_MCR_LOOP)?;
+    asm.gen(asm::or(EAX, EAX, tmp))?;
+    asm.free_temp(tmp)?;
+    asm.gen(asm::iow8(EDX, EAX))?;
+
+    // Drop anything that was already received
+    let drain = asm.new_sym_here();
+    asm.gen_load(EDX, (base + UART_LSR).into())?;
+    asm.gen_load(EAX, 0)?;
+    asm.gen(asm::ior8(EDX, EAX))?;
+    let tmp = asm.scratch()?;
+    asm.gen_load(tmp, UART_LSR_READY)?;
+    asm.gen(asm::and(EAX, EAX, tmp))?;
+    asm.free_temp(tmp)?;
+    let empty = asm.new_sym();
+    asm.gen_branch(Cond::Z, empty)?;
+    asm.gen_load(EDX, (base + UART_THR).into())?;
+    asm.gen(asm::ior8(EDX, EAX))?;
+    asm.gen_jump(drain)?;
+
+    asm.set_sym_here(empty)?;
+    asm.gen_load(EDX, (base + UART_THR).into())?;
+    asm.gen(asm::iow8(EDX, ECX))?;
+
+    asm.gen_load(ESI, LOOPBACK_TIMEOUT)?;
+    asm.gen_load(EDX, (base + UART_LSR).into())?;
+    let wait = asm.new_sym_here();
+    asm.gen_load(EAX, 0)?;
+    asm.gen(asm::ior8(EDX, EAX))?;
+    let tmp = asm.scratch()?;
+    asm.gen_load(tmp, UART_LSR_READY)?;
+    asm.gen(asm::and(EAX, EAX, tmp))?;
+    asm.free_temp(tmp)?;
+    let ready = asm.new_sym();
+    asm.gen_branch(Cond::NZ, ready)?;
+    asm.gen(asm::subi(ESI, ESI, Const::Number(1)))?;
+    asm.gen_branch(Cond::NZ, wait)?;
+    asm.gen_jump(timeout)?;
+
+    asm.set_sym_here(ready)?;
+    asm.gen_load(EDX, (base + UART_THR).into())?;
+    asm.gen_load(EAX, 0)?;
+    asm.gen(asm::ior8(EDX, EAX))?;
+    asm.gen_jump(done)?;
+
+    asm.set_sym_here(timeout)?;
+    asm.gen_load(EAX, 0xFFFF_FFFF)?;
+
+    // Restore MCR
+    asm.set_sym_here(done)?;
+    asm.gen(asm::popsp(Size::Bits32, ESI))?;
+    asm.gen_load(EDX, (base + UART_MCR).into())?;
+    asm.gen(asm::iow8(EDX, ESI))?;
+
+    asm.gen_ret()?;
+    Ok(sym)
+}
+
+// Write the zero terminated string at ESI with putc
+// Clobbers: EAX, ECX, EDX, ESI
+pub fn gen_puts(asm: &mut DynAsm, putc: Sym) -> Result<Sym, DynAsmError> {
+    let sym = asm.new_sym_here();
+    let done = asm.new_sym();
+
+    let top = asm.new_sym_here();
+    asm.gen_load(ECX, 0)?;
+    asm.gen(asm::pop(Size::Bits8L, ECX, ESI, Offset::Number(1)))?;
+    asm.gen_branch_zero(ECX, done)?;
+    asm.gen_call(putc)?;
+    asm.gen_jump(top)?;
+
+    asm.set_sym_here(done)?;
+    asm.gen_ret()?;
+    Ok(sym)
+}
+
+// Write EBX as 8 hexadecimal digits with putc
+// Clobbers: EAX, EBX, ECX, EDX, ESI
+pub fn gen_print_hex(asm: &mut DynAsm, putc: Sym) -> Result<Sym, DynAsmError> {
+    let sym = asm.new_sym_here();
+
+    asm.for_range(ESI, 8, |asm| {
+        // Take the top nibble
+        let tmp = asm.scratch()?;
+        asm.gen_load(tmp, 28)?;
+        asm.gen(asm::shr(ECX, EBX, tmp))?;
+        asm.gen_load(tmp, 4)?;
+        asm.gen(asm::shl(EBX, EBX, tmp))?;
+
+        // Carry is set for 0..9
+        asm.gen_load(tmp, 10)?;
+        asm.gen(asm::sub(tmp, ECX, tmp))?;
+        asm.free_temp(tmp)?;
+
+        asm.if_cond(
+            Cond::C,
+            |asm| asm.gen_add_imm(ECX, b'0'.into()),
+            |asm| asm.gen_add_imm(ECX, (b'A' - 10).into()),
+        )?;
+
+        asm.gen_call(putc)
+    })?;
+
+    asm.gen_ret()?;
+    Ok(sym)
+}
+
+// Store R0..R31 as 32bit words at addr, inline instead of a routine so all registers are intact.
+// The dump holds the values from before, afterwards R6 and R7 are clobbered.
+pub fn gen_dump_regs(asm: &mut DynAsm, addr: u32) -> Result<(), DynAsmError> {
+    let r6 = Register::R6;
+    let r7 = Register::R7;
+    let esp = Register::ESP;
+
+    asm.gen(asm::pushsp(Size::Bits32, r7))?;
+    asm.gen_load(r7, addr.wrapping_sub(4))?;
+    for i in 0..32 {
+        // R7 and ESP are fixed up afterwards
+        let reg = match Register(i) {
+            Register::R7 => Register::R0,
+            reg => reg,
+        };
+        asm.gen(asm::push(Size::Bits32, reg, r7, Offset::Number(4)))?;
+    }
+
+    asm.gen(asm::popsp(Size::Bits32, r6))?;
+    asm.gen_load(r7, addr + 4 * u32::from(Register::R7.0))?;
+    asm.gen(asm::push(Size::Bits32, r6, r7, Offset::Number(0)))?;
+    asm.gen_load(r7, addr + 4 * u32::from(esp.0))?;
+    asm.gen(asm::push(Size::Bits32, esp, r7, Offset::Number(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peephole::Peephole;
+    use crate::sim::{Sim, Stop, Uart16550};
+
+    const BASE: u32 = 0x48_0000;
+    const DATA: u32 = 0x20_0000;
+    const STACK: u32 = 0x10_0000;
+
+    fn run(
+        routine: impl FnOnce(&mut DynAsm) -> Result<Sym, DynAsmError>,
+        setup: impl FnOnce(&mut Sim<Uart16550>),
+    ) -> Sim<Uart16550> {
+        run_with(Peephole::default(), routine, setup)
+    }
+
+    // Place a routine, call it once and run it in the simulator
+    fn run_with(
+        peephole: Peephole,
+        routine: impl FnOnce(&mut DynAsm) -> Result<Sym, DynAsmError>,
+        setup: impl FnOnce(&mut Sim<Uart16550>),
+    ) -> Sim<Uart16550> {
+        let mut asm = DynAsm::new(BASE);
+        asm.set_peephole(peephole);
+        let start = asm.new_sym();
+        asm.gen_jump(start).unwrap();
+        let sym = routine(&mut asm).unwrap();
+        asm.set_sym_here(start).unwrap();
+        asm.gen_call(sym).unwrap();
+        let end = asm.memory().len() as u32;
+        asm.gen_footer();
+
+        let mut sim = Sim::new(Uart16550::new(COM1.into()));
+        sim.load(BASE, asm.memory());
+        sim.set_reg(Register::ESP, STACK);
+        setup(&mut sim);
+
+        assert_eq!(sim.run(BASE, 100_000).unwrap(), Stop::X86(BASE + end));
+        assert_eq!(sim.reg(Register::ESP), STACK);
+        sim
+    }
+
+    #[test]
+    fn memcpy() {
+        let sim = run(gen_memcpy, |sim| {
+            sim.load(DATA, b"Hello");
+            sim.set_reg(ESI, DATA);
+            sim.set_reg(EDI, DATA + 0x100);
+            sim.set_reg(ECX, 5);
+        });
+        assert_eq!(sim.read_bytes(DATA + 0x100, 6), b"Hello\0");
+    }
+
+    #[test]
+    fn memset() {
+        let sim = run(gen_memset, |sim| {
+            sim.set_reg(EDI, DATA + 1);
+            sim.set_reg(EAX, 0x1AA);
+            sim.set_reg(ECX, 3);
+        });
+        assert_eq!(sim.read_bytes(DATA, 5), [0, 0xAA, 0xAA, 0xAA, 0]);
+    }
+
+    #[test]
+    fn strlen() {
+        let sim = run(gen_strlen, |sim| {
+            sim.load(DATA, b"AIS\0");
+            sim.set_reg(ESI, DATA);
+        });
+        assert_eq!(sim.reg(EAX), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "unconfirmed")]
+    fn putc() {
+        let sim = run(
+            |asm| gen_putc(asm, COM1),
+            |sim| sim.set_reg(ECX, b'!'.into()),
+        );
+        assert_eq!(sim.io.tx, b"!");
+    }
+
+    #[test]
+    #[cfg(feature = "unconfirmed")]
+    fn uart_loopback() {
+        let sim = run(
+            |asm| gen_uart_loopback(asm, COM1),
+            |sim| {
+                sim.io.mcr = 0x03;
+                sim.set_reg(ECX, 0xA5);
+            },
+        );
+        assert_eq!(sim.reg(EAX), 0xA5);
+        assert!(sim.io.tx.is_empty());
+        assert_eq!(sim.io.mcr, 0x03);
+    }
+
+    #[test]
+    #[cfg(feature = "unconfirmed")]
+    fn uart_loopback_drops_stale_bytes() {
+        let sim = run(
+            |asm| gen_uart_loopback(asm, COM1),
+            |sim| {
+                sim.io.rx.extend([0x11, 0x22]);
+                sim.set_reg(ECX, 0xA5);
+            },
+        );
+        assert_eq!(sim.reg(EAX), 0xA5);
+        assert!(sim.io.rx.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "unconfirmed")]
+    fn puts() {
+        let sim = run(
+            |asm| {
+                let putc = gen_putc(asm, COM1)?;
+                gen_puts(asm, putc)
+            },
+            |sim| {
+                sim.load(DATA, b"Hello World!\n\0");
+                sim.set_reg(ESI, DATA);
+            },
+        );
+        assert_eq!(sim.io.tx, b"Hello World!\n");
+    }
+
+    #[test]
+    #[cfg(feature = "unconfirmed")]
+    fn print_hex() {
+        let sim = run(
+            |asm| {
+                let putc = gen_putc(asm, COM1)?;
+                gen_print_hex(asm, putc)
+            },
+            |sim| sim.set_reg(EBX, 0x0BAD_C0DE),
+        );
+        assert_eq!(sim.io.tx, b"0BADC0DE");
+    }
+
+    #[test]
+    #[cfg(feature = "unconfirmed")]
+    fn print_hex_optimised() {
+        let sim = run_with(
+            Peephole::all(),
+            |asm| {
+                let putc = gen_putc(asm, COM1)?;
+                gen_print_hex(asm, putc)
+            },
+            |sim| sim.set_reg(EBX, 0x0123_ABCD),
+        );
+        assert_eq!(sim.io.tx, b"0123ABCD");
+    }
+
+    #[test]
+    fn dump_regs() {
+        let mut asm = DynAsm::new(BASE);
+        gen_dump_regs(&mut asm, DATA).unwrap();
+        asm.gen_footer();
+
+        let mut sim = Sim::new(Uart16550::new(COM1.into()));
+        sim.load(BASE, asm.memory());
+        for i in 1..32 {
+            sim.regs[i] = 0x100 + i as u32;
+        }
+        sim.set_reg(Register::ESP, STACK);
+        sim.run(BASE, 1000).unwrap();
+
+        for i in 0..32 {
+            let expected = match Register(i) {
+                Register::R0 => 0,
+                Register::ESP => STACK,
+                _ => 0x100 + u32::from(i),
+            };
+            assert_eq!(sim.read(DATA + 4 * u32::from(i), 4), expected, "R{}", i);
+        }
+    }
+}
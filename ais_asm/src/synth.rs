@@ -0,0 +1,191 @@
+// Constant synthesis.
+//
+// Picks the cheapest sequence that loads a value into a register. Single instruction candidates
+// are ORI and ORIU from R0, and with the unconfirmed feature XLEAD with an offset from the table
+// and ADDI. Anything else is loaded with ORI + ORIU. XALUI is not a candidate, all constants seen
+// on hardware are small positive numbers that ORI loads as well.
+//
+// None of the costs have been measured. Every instruction is assumed to take a cycle, except
+// XLEAD, which goes through address generation and is assumed to take two. When the cost is the
+// same the table based form is preferred. Neither the Offset table nor the sign extension of the
+// ADDI immediate has been confirmed on hardware.
+
+#[cfg(feature = "unconfirmed")]
+use crate::ais::{AddrSize, Offset, Size};
+use crate::ais::{Instruction, Opcode, Register};
+use crate::asm;
+use crate::dynasm::{DynAsm, DynAsmError};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Goal {
+    Size,    // Fewest instructions
+    Latency, // Shortest estimated dependency chain
+}
+
+fn latency(instr: &Instruction) -> u32 {
+    match instr.opcode {
+        Opcode::XLEAD => 2,
+        _ => 1,
+    }
+}
+
+// Single instruction loads of value, in order of preference
+fn single(dst: Register, value: u32) -> Vec<Instruction> {
+    let r0 = Register::R0;
+    let mut candidates = Vec::new();
+
+    if value >> 16 == 0 {
+        candidates.push(asm::xori(dst, r0, value as u16));
+    }
+    if value & 0xFFFF == 0 {
+        candidates.push(asm::xoriu(dst, r0, (value >> 16) as u16));
+    }
+    #[cfg(feature = "unconfirmed")]
+    candidates.extend(unconfirmed(dst, value));
+
+    candidates
+}
+
+#[cfg(feature = "unconfirmed")]
+fn unconfirmed(dst: Register, value: u32) -> Vec<Instruction> {
+    let r0 = Register::R0;
+    let signed = value as i32;
+    let mut candidates = Vec::new();
+
+    if let Ok(c) = i8::try_from(signed) {
+        if TryInto::<u8>::try_into(Offset::Number(c)).is_ok() {
+            let offset = Offset::Number(c);
+            candidates.push(asm::lead(dst, r0, offset, AddrSize::Bits32, Size::Bits32));
+        }
+    }
+    if let Ok(imm) = i16::try_from(signed) {
+        candidates.push(asm::xaddi(dst, r0, imm as u16));
+    }
+
+    candidates
+}
+
+pub fn synthesize(dst: Register, value: u32, goal: Goal) -> Vec<Instruction> {
+    let mut candidates: Vec<Vec<Instruction>> = single(dst, value)
+        .into_iter()
+        .map(|instr| vec![instr])
+        .collect();
+    candidates.push(vec![
+        asm::xori(dst, Register::R0, value as u16),
+        asm::xoriu(dst, dst, (value >> 16) as u16),
+    ]);
+
+    // min_by_key keeps the first of equal candidates
+    candidates
+        .into_iter()
+        .min_by_key(|instrs| {
+            let count = instrs.len() as u32;
+            match goal {
+                Goal::Size => (count, 0),
+                Goal::Latency => (instrs.iter().map(latency).sum(), count),
+            }
+        })
+        .unwrap()
+}
+
+impl DynAsm {
+    pub fn gen_load_with(
+        &mut self,
+        dst: Register,
+        imm: u32,
+        goal: Goal,
+    ) -> Result<(), DynAsmError> {
+        for instr in synthesize(dst, imm, goal) {
+            self.gen(instr)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn synthesized_loads_match_value() {
+    use crate::sim::{NoIo, Sim};
+
+    let values = [
+        0,
+        1,
+        6,
+        0x1234,
+        0x8000,
+        0x4_0000,
+        0xFFFF_FFFF,
+        0xFFFF_FFF8,
+        0xFFFF_8000,
+        0x1234_5678,
+    ];
+
+    for goal in [Goal::Size, Goal::Latency] {
+        for value in values {
+            let mut asm = DynAsm::new(0x1000);
+            asm.gen_load_with(Register::EAX, value, goal).unwrap();
+            asm.gen(asm::jx86(Register::R0)).unwrap();
+
+            let mut sim = Sim::new(NoIo);
+            sim.load(0x1000, asm.memory());
+            sim.run(0x1000, 10).unwrap();
+            assert_eq!(sim.reg(Register::EAX), value, "{:X} {:?}", value, goal);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "unconfirmed")]
+fn table_forms_are_preferred() {
+    let eax = Register::EAX;
+
+    // Table offset versus a sign extended immediate
+    assert_eq!(
+        synthesize(eax, -8i32 as u32, Goal::Size)[0].opcode,
+        Opcode::XLEAD
+    );
+    assert_eq!(
+        synthesize(eax, -8i32 as u32, Goal::Latency)[0].opcode,
+        Opcode::ADDI
+    );
+    for goal in [Goal::Size, Goal::Latency] {
+        assert_eq!(
+            synthesize(eax, -100i32 as u32, goal)[0].opcode,
+            Opcode::ADDI
+        );
+    }
+
+    assert_eq!(synthesize(eax, 0x1234_5678, Goal::Size).len(), 2);
+    assert_eq!(
+        synthesize(eax, 0x5_0000, Goal::Latency)[0].opcode,
+        Opcode::ORIU
+    );
+}
+
+#[test]
+fn small_constants_use_ori() {
+    use crate::ais::CONST_OBSERVED;
+
+    for goal in [Goal::Size, Goal::Latency] {
+        for &(_, value) in CONST_OBSERVED {
+            let instrs = synthesize(Register::EAX, value as u32, goal);
+            assert_eq!(instrs.len(), 1);
+            assert_eq!(instrs[0].opcode, Opcode::ORI);
+        }
+    }
+}
+
+#[test]
+fn unconfirmed_forms_follow_the_feature() {
+    let enabled = cfg!(feature = "unconfirmed");
+
+    for goal in [Goal::Size, Goal::Latency] {
+        let instrs = synthesize(Register::EAX, -100i32 as u32, goal);
+        assert_eq!(instrs.len() == 1, enabled);
+        for value in (-64..=64).chain([0x1000, 0x1_0000, 0x7FFF_FFFF]) {
+            for instr in synthesize(Register::EAX, value as u32, goal) {
+                let table = matches!(instr.opcode, Opcode::XLEAD | Opcode::ADDI);
+                assert!(enabled || !table, "{} {:?} {:?}", value, goal, instr);
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// Translator state that the special Offset values depend on.
+//
+// The reference only names the special offsets. Their meaning below is our reading of the names
+// and has not been confirmed on hardware:
+// OS    +operand size (TSR.OS)
+// PDOS  +2 * operand size
+// MOS   -operand size
+// MGS   -gate size
+// MDOS  -2 * operand size
+// DF    +1 or -1 depending on EFLAGS.DF, like the x86 string instructions
+// DFOS  +operand size or -operand size depending on EFLAGS.DF
+// DISP  displacement of the x86 instruction being translated
+
+use crate::ais::Offset;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dependency {
+    OperandSize,
+    GateSize,
+    DirectionFlag,
+    Disp,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TranslatorState {
+    pub os: u32, // Operand size in bytes
+    pub gs: u32, // Gate size in bytes
+    pub df: bool,
+    pub disp: u32,
+}
+
+// 32bit code segment with the direction flag clear
+impl Default for TranslatorState {
+    fn default() -> Self {
+        Self {
+            os: 4,
+            gs: 4,
+            df: false,
+            disp: 0,
+        }
+    }
+}
+
+// State a special offset depends on, empty for plain numbers and unknown encodings
+pub fn dependencies(offset: Offset) -> &'static [Dependency] {
+    match offset {
+        Offset::OS | Offset::PDOS | Offset::MOS | Offset::MDOS => &[Dependency::OperandSize],
+        Offset::MGS => &[Dependency::GateSize],
+        Offset::DF => &[Dependency::DirectionFlag],
+        Offset::DFOS => &[Dependency::DirectionFlag, Dependency::OperandSize],
+        Offset::DISP => &[Dependency::Disp],
+        Offset::Number(_) | Offset::Raw(_) => &[],
+    }
+}
+
+impl TranslatorState {
+    // Effective offset, None when the encoding is not known
+    pub fn resolve(&self, offset: Offset) -> Option<u32> {
+        let step = |size: u32| match self.df {
+            false => size,
+            true => size.wrapping_neg(),
+        };
+
+        Some(match offset {
+            Offset::Number(x) => x as i32 as u32,
+            Offset::OS => self.os,
+            Offset::PDOS => 2 * self.os,
+            Offset::MOS => self.os.wrapping_neg(),
+            Offset::MGS => self.gs.wrapping_neg(),
+            Offset::MDOS => (2 * self.os).wrapping_neg(),
+            Offset::DF => step(1),
+            Offset::DFOS => step(self.os),
+            Offset::DISP => self.disp,
+            Offset::Raw(_) => return None,
+        })
+    }
+}
+
+#[test]
+fn special_offsets_follow_state() {
+    let state = TranslatorState {
+        os: 2,
+        gs: 4,
+        df: true,
+        disp: 0x40,
+    };
+
+    assert_eq!(state.resolve(Offset::Number(-4)), Some(-4i32 as u32));
+    assert_eq!(state.resolve(Offset::PDOS), Some(4));
+    assert_eq!(state.resolve(Offset::MGS), Some(-4i32 as u32));
+    assert_eq!(state.resolve(Offset::DF), Some(-1i32 as u32));
+    assert_eq!(state.resolve(Offset::DFOS), Some(-2i32 as u32));
+    assert_eq!(state.resolve(Offset::DISP), Some(0x40));
+    assert_eq!(state.resolve(Offset::Raw(0b01101)), None);
+
+    let state = TranslatorState::default();
+    assert_eq!(state.resolve(Offset::DFOS), Some(4));
+}
@@ -0,0 +1,507 @@
+// Typed view of Instruction, with one variant per instruction format.
+//
+// Each format only has the fields it encodes and only accepts the opcodes that use it, so a missing
+// register or an XALU function on an XIOW can't be expressed. Converting to Instruction always
+// works, converting back fails with Missing or Unsupported when the fields don't fit the format.
+
+use crate::ais::{
+    AddrSize, AisError, Const, DpCntl, Field, Function, Instruction, Offset, Opcode, Register, Sel,
+    Size, SubFunc, SubOp, SubOpXalu, SubOpXio, XjCond, XjMode, XjSize,
+};
+use crate::asm::Flags;
+
+// Subset of Opcode, with conversions in both directions
+macro_rules! opcodes {
+    ($name:ident { $($op:ident),* }) => {
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(Debug, Copy, Clone, PartialEq)]
+        pub enum $name {
+            $($op),*
+        }
+
+        impl From<$name> for Opcode {
+            fn from(x: $name) -> Self {
+                match x {
+                    $($name::$op => Opcode::$op),*
+                }
+            }
+        }
+
+        impl TryFrom<Opcode> for $name {
+            type Error = AisError;
+
+            fn try_from(x: Opcode) -> Result<Self, Self::Error> {
+                match x {
+                    $(Opcode::$op => Ok($name::$op),)*
+                    _ => Err(AisError::Unsupported(Field::Opcode)),
+                }
+            }
+        }
+    };
+}
+
+opcodes!(IOpcode {
+    ORIU,
+    ADDI,
+    ANDIU,
+    ANDIL,
+    ANDI,
+    ORI,
+    XORI,
+    XORIU
+});
+opcodes!(XlsOpcode {
+    XL,
+    XL2,
+    XL3,
+    XLBI,
+    XLDESC,
+    XPOP,
+    XS,
+    XS2,
+    XSI,
+    XPUSHIP,
+    XSU,
+    XPUSH
+});
+opcodes!(XioOpcode { XIOR, XIOW });
+
+#[derive(Debug, Copy, Clone)]
+pub struct IType {
+    pub opcode: IOpcode,
+    pub rs: Register,
+    pub rt: Register,
+    pub imm: u16,
+}
+
+// XALU and XALUR
+#[derive(Debug, Copy, Clone)]
+pub struct Xalu {
+    pub flags: Flags,
+    pub sub_op: SubOpXalu,
+    pub dp_cntl: DpCntl,
+    pub rs: Register,
+    pub rt: Register,
+    pub rd: Register,
+}
+
+// XALUI and XALUIR
+#[derive(Debug, Copy, Clone)]
+pub struct Xalui {
+    pub flags: Flags,
+    pub sub_op: SubOpXalu,
+    pub dp_cntl: DpCntl,
+    pub rs: Register,
+    pub constant: Const,
+    pub rd: Register,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Xmisc {
+    pub sub_func: SubFunc,
+    pub other: u8,
+    pub rs: Register,
+    pub rt: Register,
+    pub rd: Register,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Xls {
+    pub opcode: XlsOpcode,
+    pub sub_op: SubOp,
+    pub addr_size: AddrSize,
+    pub size: Size,
+    pub sel: Sel,
+    pub reg: Register,
+    pub base: Register,
+    pub offset: Offset,
+}
+
+// XLS format with the XIO function
+#[derive(Debug, Copy, Clone)]
+pub struct Xio {
+    pub opcode: XioOpcode,
+    pub sub_op: SubOpXio,
+    pub addr_size: AddrSize,
+    pub size: Size,
+    pub sel: Sel,
+    pub reg: Register,
+    pub port: Register,
+    pub offset: Offset,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Xj {
+    pub size: XjSize,
+    pub cond: XjCond,
+    pub mode: XjMode,
+    pub base: Register,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Xlead {
+    pub addr_size: AddrSize,
+    pub size: Size,
+    pub dst: Register,
+    pub base: Register,
+    pub offset: Offset,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Xleai {
+    pub addr_size: AddrSize,
+    pub size: Size,
+    pub dst: Register,
+    pub base: Register,
+    pub index: Register,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Typed {
+    I(IType),
+    Xalu(Xalu),
+    Xalui(Xalui),
+    Xmisc(Xmisc),
+    Xls(Xls),
+    Xio(Xio),
+    Xj(Xj),
+    Xlead(Xlead),
+    Xleai(Xleai),
+}
+
+fn required<T>(x: Option<T>, field: Field) -> Result<T, AisError> {
+    x.ok_or(AisError::Missing(field))
+}
+
+fn unsupported<T>() -> Result<T, AisError> {
+    Err(AisError::Unsupported(Field::Function))
+}
+
+impl From<Typed> for Instruction {
+    fn from(typed: Typed) -> Self {
+        let opcode = match typed {
+            Typed::I(x) => x.opcode.into(),
+            Typed::Xalu(x) => match x.flags {
+                Flags::Keep => Opcode::XALU,
+                Flags::Update => Opcode::XALUR,
+            },
+            Typed::Xalui(x) => match x.flags {
+                Flags::Keep => Opcode::XALUI,
+                Flags::Update => Opcode::XALUIR,
+            },
+            Typed::Xmisc(_) => Opcode::XMISC,
+            Typed::Xls(x) => x.opcode.into(),
+            Typed::Xio(x) => x.opcode.into(),
+            Typed::Xj(_) => Opcode::XJ,
+            Typed::Xlead(_) => Opcode::XLEAD,
+            Typed::Xleai(_) => Opcode::XLEAI,
+        };
+
+        let mut instr = Instruction::new(opcode);
+        match typed {
+            Typed::I(x) => {
+                instr.rs = Some(x.rs);
+                instr.rt = Some(x.rt);
+                instr.imm = Some(x.imm);
+            }
+            Typed::Xalu(x) => {
+                instr.rs = Some(x.rs);
+                instr.rt = Some(x.rt);
+                instr.rd = Some(x.rd);
+                instr.function = Some(Function::Xalu(x.sub_op, x.dp_cntl));
+            }
+            Typed::Xalui(x) => {
+                instr.rs = Some(x.rs);
+                instr.constant = Some(x.constant);
+                instr.rd = Some(x.rd);
+                instr.function = Some(Function::Xalu(x.sub_op, x.dp_cntl));
+            }
+            Typed::Xmisc(x) => {
+                instr.rs = Some(x.rs);
+                instr.rt = Some(x.rt);
+                instr.rd = Some(x.rd);
+                instr.function = Some(Function::Xmisc(x.sub_func, x.other));
+            }
+            Typed::Xls(x) => {
+                instr.rs = Some(x.reg);
+                instr.rt = Some(x.base);
+                instr.offset = Some(x.offset);
+                instr.function = Some(Function::Xls(x.sub_op, x.addr_size, x.size, x.sel));
+            }
+            Typed::Xio(x) => {
+                instr.rs = Some(x.reg);
+                instr.rt = Some(x.port);
+                instr.offset = Some(x.offset);
+                instr.function = Some(Function::Xio(x.sub_op, x.addr_size, x.size, x.sel));
+            }
+            Typed::Xj(x) => {
+                instr.rt = Some(x.base);
+                instr.function = Some(Function::Xj(x.size, x.cond, x.mode));
+            }
+            Typed::Xlead(x) => {
+                instr.rs = Some(x.dst);
+                instr.rt = Some(x.base);
+                instr.offset = Some(x.offset);
+                instr.function = Some(Function::Xlea(x.addr_size, x.size));
+            }
+            Typed::Xleai(x) => {
+                instr.rs = Some(x.dst);
+                instr.rt = Some(x.base);
+                instr.rd = Some(x.index);
+                instr.function = Some(Function::Xlea(x.addr_size, x.size));
+            }
+        }
+        instr
+    }
+}
+
+impl TryFrom<Instruction> for Typed {
+    type Error = AisError;
+
+    // Fields that the format does not use must be empty, leftover bits can't be represented
+    fn try_from(instr: Instruction) -> Result<Self, Self::Error> {
+        let unused = |used: [bool; 6]| {
+            let present = [
+                instr.rs.is_some(),
+                instr.rt.is_some(),
+                instr.rd.is_some(),
+                instr.imm.is_some(),
+                instr.constant.is_some(),
+                instr.offset.is_some(),
+            ];
+            let fields = [
+                Field::RS,
+                Field::RT,
+                Field::RD,
+                Field::Immediate,
+                Field::Const,
+                Field::Offset,
+            ];
+            match present.iter().zip(used).position(|(p, u)| *p && !u) {
+                Some(i) => Err(AisError::Unsupported(fields[i])),
+                None => Ok(()),
+            }
+        };
+        if instr.leftovers != 0 {
+            return unsupported();
+        }
+
+        let rs = || required(instr.rs, Field::RS);
+        let rt = || required(instr.rt, Field::RT);
+        let rd = || required(instr.rd, Field::RD);
+        let offset = || required(instr.offset, Field::Offset);
+        let function = required(instr.function, Field::Function);
+
+        let flags = match instr.opcode {
+            Opcode::XALUR | Opcode::XALUIR => Flags::Update,
+            _ => Flags::Keep,
+        };
+
+        let typed = match instr.opcode {
+            op if instr.is_i_type() => {
+                unused([true, true, false, true, false, false])?;
+                Typed::I(IType {
+                    opcode: op.try_into()?,
+                    rs: rs()?,
+                    rt: rt()?,
+                    imm: required(instr.imm, Field::Immediate)?,
+                })
+            }
+            Opcode::XALU | Opcode::XALUR => {
+                unused([true, true, true, false, false, false])?;
+                let Function::Xalu(sub_op, dp_cntl) = function? else {
+                    return unsupported();
+                };
+                Typed::Xalu(Xalu {
+                    flags,
+                    sub_op,
+                    dp_cntl,
+                    rs: rs()?,
+                    rt: rt()?,
+                    rd: rd()?,
+                })
+            }
+            Opcode::XALUI | Opcode::XALUIR => {
+                unused([true, false, true, false, true, false])?;
+                let Function::Xalu(sub_op, dp_cntl) = function? else {
+                    return unsupported();
+                };
+                Typed::Xalui(Xalui {
+                    flags,
+                    sub_op,
+                    dp_cntl,
+                    rs: rs()?,
+                    constant: required(instr.constant, Field::Const)?,
+                    rd: rd()?,
+                })
+            }
+            Opcode::XMISC => {
+                unused([true, true, true, false, false, false])?;
+                let Function::Xmisc(sub_func, other) = function? else {
+                    return unsupported();
+                };
+                Typed::Xmisc(Xmisc {
+                    sub_func,
+                    other,
+                    rs: rs()?,
+                    rt: rt()?,
+                    rd: rd()?,
+                })
+            }
+            Opcode::XIOR | Opcode::XIOW => {
+                unused([true, true, false, false, false, true])?;
+                let Function::Xio(sub_op, addr_size, size, sel) = function? else {
+                    return unsupported();
+                };
+                Typed::Xio(Xio {
+                    opcode: instr.opcode.try_into()?,
+                    sub_op,
+                    addr_size,
+                    size,
+                    sel,
+                    reg: rs()?,
+                    port: rt()?,
+                    offset: offset()?,
+                })
+            }
+            Opcode::XJ => {
+                unused([false, true, false, false, false, false])?;
+                let Function::Xj(size, cond, mode) = function? else {
+                    return unsupported();
+                };
+                Typed::Xj(Xj {
+                    size,
+                    cond,
+                    mode,
+                    base: rt()?,
+                })
+            }
+            Opcode::XLEAD => {
+                unused([true, true, false, false, false, true])?;
+                let Function::Xlea(addr_size, size) = function? else {
+                    return unsupported();
+                };
+                Typed::Xlead(Xlead {
+                    addr_size,
+                    size,
+                    dst: rs()?,
+                    base: rt()?,
+                    offset: offset()?,
+                })
+            }
+            Opcode::XLEAI => {
+                unused([true, true, true, false, false, false])?;
+                let Function::Xlea(addr_size, size) = function? else {
+                    return unsupported();
+                };
+                Typed::Xleai(Xleai {
+                    addr_size,
+                    size,
+                    dst: rs()?,
+                    base: rt()?,
+                    index: rd()?,
+                })
+            }
+            op => {
+                unused([true, true, false, false, false, true])?;
+                let opcode = op.try_into()?;
+                let Function::Xls(sub_op, addr_size, size, sel) = function? else {
+                    return unsupported();
+                };
+                Typed::Xls(Xls {
+                    opcode,
+                    sub_op,
+                    addr_size,
+                    size,
+                    sel,
+                    reg: rs()?,
+                    base: rt()?,
+                    offset: offset()?,
+                })
+            }
+        };
+
+        Ok(typed)
+    }
+}
+
+impl Typed {
+    pub fn encode(self) -> Result<Vec<u8>, AisError> {
+        Instruction::from(self).encode()
+    }
+}
+
+#[test]
+fn builders_convert_losslessly() {
+    use crate::ais::{Cond, Cp2Reg};
+    use crate::asm;
+
+    let (eax, ecx, edx) = (Register::EAX, Register::ECX, Register::EDX);
+    let instrs = [
+        asm::xori(eax, ecx, 0x1234),
+        asm::add(eax, ecx, edx),
+        asm::addi_nf(eax, ecx, Const::Number(5)),
+        asm::cfc2(eax, Cp2Reg::EFLAGS),
+        asm::ctc2(Cp2Reg::EFLAGS, eax),
+        asm::pushsp(Size::Bits32, eax),
+        asm::load(
+            Size::Bits8H,
+            eax,
+            ecx,
+            Offset::OS,
+            Sel::GDT,
+            AddrSize::Bits16,
+        ),
+        asm::ior8(edx, eax),
+        asm::jcc(Cond::NZ, eax),
+        asm::lead(eax, ecx, Offset::DFOS, AddrSize::Bits32, Size::Bits32),
+        asm::leai(eax, ecx, edx, AddrSize::Bits32, Size::Bits32),
+    ];
+
+    for instr in instrs {
+        let typed = Typed::try_from(instr).unwrap();
+        let back = Instruction::from(typed);
+        assert_eq!(format!("{:?}", back), format!("{:?}", instr));
+    }
+}
+
+#[test]
+fn mismatches_are_rejected() {
+    use crate::asm;
+
+    let mut instr = asm::iow8(Register::EDX, Register::EAX);
+    instr.function = Some(Function::Xalu(SubOpXalu::ADD, DpCntl::Word));
+    assert!(matches!(
+        Typed::try_from(instr),
+        Err(AisError::Unsupported(Field::Function))
+    ));
+
+    let mut instr = asm::add(Register::EAX, Register::ECX, Register::EDX);
+    instr.rd = None;
+    assert!(matches!(
+        Typed::try_from(instr),
+        Err(AisError::Missing(Field::RD))
+    ));
+
+    instr.opcode = Opcode::XPOPBR;
+    assert!(Typed::try_from(instr).is_err());
+}
+
+#[test]
+fn dynasm_accepts_typed() {
+    use crate::dynasm::DynAsm;
+
+    let typed = Typed::Xalu(Xalu {
+        flags: Flags::Update,
+        sub_op: SubOpXalu::OR,
+        dp_cntl: DpCntl::Word,
+        rs: Register::ECX,
+        rt: Register::R0,
+        rd: Register::EAX,
+    });
+
+    let mut asm = DynAsm::new(0x48_0000);
+    asm.gen(typed).unwrap();
+    let expected = crate::asm::or(Register::EAX, Register::ECX, Register::R0);
+    assert_eq!(asm.memory(), &expected.encode().unwrap());
+}
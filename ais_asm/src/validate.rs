@@ -0,0 +1,146 @@
+// Semantic checks for instructions that encode fine but don't do what was meant.
+
+use crate::ais::{Cp2Reg, Function, Instruction, Opcode, Register, Sel, Size, SubOpXalu};
+use crate::peephole::writes;
+use crate::translator::{self, Dependency};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Reason {
+    WritesR0,                     // Result is discarded and no flags are updated
+    IoSize64,                     // Ports are at most 32bit wide
+    StoreThroughCs,               // x86 never allows writes through CS
+    NotSegmentRegister(Register), // XLDESC into a register other than CS..GS
+    TranslatorState(Dependency),  // Special offset used before its translator state was set up
+    Undecodable,                  // Only reported for buffers
+}
+
+impl Reason {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Reason::WritesR0 => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+// Finding at a byte offset of a buffer
+#[derive(Debug, Copy, Clone)]
+pub struct Diagnostic {
+    pub offset: u32,
+    pub reason: Reason,
+}
+
+// Checks instructions in program order, keeping track of the translator state that was set up
+#[derive(Debug, Clone, Default)]
+pub struct Validator {
+    established: Vec<Dependency>,
+}
+
+fn is_store(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::XS | Opcode::XS2 | Opcode::XSI | Opcode::XSU | Opcode::XPUSH | Opcode::XPUSHIP
+    )
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn establish(&mut self, dep: Dependency) {
+        if !self.established.contains(&dep) {
+            self.established.push(dep);
+        }
+    }
+
+    pub fn check(&mut self, instr: &Instruction) -> Vec<Reason> {
+        let mut reasons = Vec::new();
+
+        // CTC2 names a CP2 register in rd, storing EFLAGS sets up the direction flag
+        let ctc2 = matches!(instr.function, Some(Function::Xalu(SubOpXalu::CTC2, _)));
+        if ctc2 && instr.rd == Some(Register(Cp2Reg::EFLAGS.0)) {
+            self.establish(Dependency::DirectionFlag);
+        }
+
+        // XALUR and XALUIR into R0 are a compare
+        let flags = matches!(instr.opcode, Opcode::XALUR | Opcode::XALUIR);
+        let written = writes(instr).unwrap_or_default();
+        if !ctc2 && !flags && written.contains(&Register::R0) {
+            reasons.push(Reason::WritesR0);
+        }
+
+        match instr.function {
+            Some(Function::Xio(_, _, Size::Bits64, _)) => reasons.push(Reason::IoSize64),
+            Some(Function::Xls(_, _, _, Sel::CS)) if is_store(instr.opcode) => {
+                reasons.push(Reason::StoreThroughCs)
+            }
+            _ => {}
+        }
+
+        if let (Opcode::XLDESC, Some(reg)) = (instr.opcode, instr.rs) {
+            if !(Register::CS.0..=Register::GS.0).contains(&reg.0) {
+                reasons.push(Reason::NotSegmentRegister(reg));
+            }
+        }
+
+        for dep in instr.offset.map_or(&[][..], translator::dependencies) {
+            if !self.established.contains(dep) {
+                reasons.push(Reason::TranslatorState(*dep));
+            }
+        }
+
+        reasons
+    }
+}
+
+#[test]
+fn nonsense_is_reported() {
+    use crate::ais::{AddrSize, Offset};
+    use crate::asm;
+
+    let (eax, edx, r0) = (Register::EAX, Register::EDX, Register::R0);
+    let mut validator = Validator::new();
+
+    assert_eq!(validator.check(&asm::xori(r0, eax, 1)), [Reason::WritesR0]);
+    assert!(validator.check(&asm::sub(r0, eax, edx)).is_empty());
+    assert_eq!(
+        validator.check(&asm::iow(Size::Bits64, edx, eax)),
+        [Reason::IoSize64]
+    );
+
+    let store = asm::store(
+        Size::Bits32,
+        eax,
+        edx,
+        Offset::DISP,
+        Sel::CS,
+        AddrSize::Bits32,
+    );
+    let reasons = validator.check(&store);
+    assert_eq!(
+        reasons,
+        [
+            Reason::StoreThroughCs,
+            Reason::TranslatorState(Dependency::Disp)
+        ]
+    );
+    assert_eq!(reasons[0].severity(), Severity::Error);
+
+    validator.establish(Dependency::Disp);
+    let load = asm::load(
+        Size::Bits32,
+        eax,
+        edx,
+        Offset::DISP,
+        Sel::CS,
+        AddrSize::Bits32,
+    );
+    assert!(validator.check(&load).is_empty());
+}
@@ -0,0 +1,118 @@
+// Encoders for the small set of x86 instructions that are useful around AIS code.
+// AIS registers EAX..EDI alias the x86 general purpose registers.
+
+use crate::ais::Register;
+
+#[derive(Debug)]
+pub enum X86Error {
+    Register(Register),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Width {
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+const OPERAND_SIZE_PREFIX: u8 = 0x66;
+
+fn index(reg: Register) -> Result<u8, X86Error> {
+    match reg {
+        Register(x) if (Register::EAX.0..=Register::EDI.0).contains(&x) => Ok(x - Register::EAX.0),
+        _ => Err(X86Error::Register(reg)),
+    }
+}
+
+fn modrm_reg_reg(reg: u8, rm: u8) -> u8 {
+    0b11 << 6 | reg << 3 | rm
+}
+
+// mov reg, imm32
+pub fn mov_imm(dst: Register, imm: u32) -> Result<Vec<u8>, X86Error> {
+    let mut bytes = vec![0xB8 + index(dst)?];
+    bytes.extend_from_slice(&imm.to_le_bytes());
+    Ok(bytes)
+}
+
+// mov dst, src
+pub fn mov(dst: Register, src: Register) -> Result<Vec<u8>, X86Error> {
+    Ok(vec![0x89, modrm_reg_reg(index(src)?, index(dst)?)])
+}
+
+pub fn push(reg: Register) -> Result<Vec<u8>, X86Error> {
+    Ok(vec![0x50 + index(reg)?])
+}
+
+// push imm32
+pub fn push_imm(imm: u32) -> Vec<u8> {
+    let mut bytes = vec![0x68];
+    bytes.extend_from_slice(&imm.to_le_bytes());
+    bytes
+}
+
+pub fn pop(reg: Register) -> Result<Vec<u8>, X86Error> {
+    Ok(vec![0x58 + index(reg)?])
+}
+
+// call rel32, relative to the end of the instruction
+pub fn call_rel(rel: i32) -> Vec<u8> {
+    let mut bytes = vec![0xE8];
+    bytes.extend_from_slice(&rel.to_le_bytes());
+    bytes
+}
+
+// call reg
+pub fn call_reg(reg: Register) -> Result<Vec<u8>, X86Error> {
+    Ok(vec![0xFF, modrm_reg_reg(2, index(reg)?)])
+}
+
+pub fn ret() -> Vec<u8> {
+    vec![0xC3]
+}
+
+// out dx, al/ax/eax
+pub fn out(width: Width) -> Vec<u8> {
+    match width {
+        Width::Bits8 => vec![0xEE],
+        Width::Bits16 => vec![OPERAND_SIZE_PREFIX, 0xEF],
+        Width::Bits32 => vec![0xEF],
+    }
+}
+
+// in al/ax/eax, dx
+pub fn inp(width: Width) -> Vec<u8> {
+    match width {
+        Width::Bits8 => vec![0xEC],
+        Width::Bits16 => vec![OPERAND_SIZE_PREFIX, 0xED],
+        Width::Bits32 => vec![0xED],
+    }
+}
+
+pub fn cpuid() -> Vec<u8> {
+    vec![0x0F, 0xA2]
+}
+
+pub fn rdtsc() -> Vec<u8> {
+    vec![0x0F, 0x31]
+}
+
+// JMPAI always jumps to EAX, so other registers are first moved into EAX
+pub fn jmpai(reg: Register) -> Result<Vec<u8>, X86Error> {
+    let mut bytes = Vec::new();
+    if reg != Register::EAX {
+        bytes.extend(mov(Register::EAX, reg)?);
+    }
+    bytes.extend_from_slice(&[0x0F, 0x3F]);
+    Ok(bytes)
+}
+
+#[test]
+fn encodings() {
+    assert_eq!(mov_imm(Register::EAX, 6).unwrap(), [0xB8, 6, 0, 0, 0]);
+    assert_eq!(mov(Register::EAX, Register::EBX).unwrap(), [0x89, 0xD8]);
+    assert_eq!(pop(Register::EAX).unwrap(), [0x58]);
+    assert_eq!(call_reg(Register::EDX).unwrap(), [0xFF, 0xD2]);
+    assert_eq!(jmpai(Register::EAX).unwrap(), [0x0F, 0x3F]);
+    assert!(push(Register::R4).is_err());
+}
@@ -66,3 +66,16 @@ pub fn cpuid(val: u32) -> [u32; 4] {
 
     [eax, ebx, ecx, edx]
 }
+
+// Base and limit of the GDT
+#[inline]
+pub fn sgdt() -> (u32, u16) {
+    let mut gdtr = [0u8; 6];
+    unsafe {
+        asm!("sgdt [{}]", in(reg) gdtr.as_mut_ptr(), options(nostack, preserves_flags));
+    }
+
+    let limit = u16::from_le_bytes([gdtr[0], gdtr[1]]);
+    let base = u32::from_le_bytes([gdtr[2], gdtr[3], gdtr[4], gdtr[5]]);
+    (base, limit)
+}
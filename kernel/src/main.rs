@@ -36,6 +36,13 @@ pub fn multiboot_entry(_: &[u8]) {
 
     println!("EFLAGS {:08X}", asm::flags());
 
+    // Wait for the messages above to leave, loopback mode disconnects the transmitter
+    while !SERIAL1.lock().tx_empty() {
+        core::hint::spin_loop()
+    }
+    let loopback = SERIAL1.lock().loopback_test();
+    println!("COM1 UART check (x86) {}", if loopback { "ok" } else { "FAILED" });
+
     let reg_dump: &mut [u32; 32] = unsafe { core::mem::transmute(0x50_0000) };
 
     for i in reg_dump.iter_mut() {
@@ -60,6 +67,15 @@ pub fn multiboot_entry(_: &[u8]) {
         println!("0x{:08X}", i);
     }
 
+    // Reference for payloads that read the GDT from AIS, like the gdt example of ais_asm
+    let (base, limit) = asm::sgdt();
+    println!("GDT base 0x{:08X} limit 0x{:04X}", base, limit);
+    let entries = (usize::from(limit) + 1) / 8;
+    let gdt: &[u32] = unsafe { core::slice::from_raw_parts(base as *const u32, 2 * entries) };
+    for entry in gdt.chunks(2) {
+        println!("0x{:08X} 0x{:08X}", entry[0], entry[1]);
+    }
+
     println!("Done");
     loop {
         asm::halt()
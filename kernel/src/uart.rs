@@ -18,6 +18,8 @@ const THR: Register = Register(0); // write-only
 const DLL: Register = Register(0); // when DLAB = 1
 const DLM: Register = Register(1); // when DLAB = 1
 const LCR: Register = Register(3);
+const MCR: Register = Register(4);
+const MCR_LOOP: u8 = 0x10;
 const LCR_DLAB: u8 = 0x80;
 const LCR_8BITS: u8 = 0x03;
 const LSR: Register = Register(5);
@@ -64,6 +66,30 @@ impl Uart {
         self.read(LSR) & LSR_READY == LSR_READY
     }
 
+    // Send a byte to ourselves in loopback mode from x86, true when it comes back. This only checks
+    // the UART, XIOR and XIOW are checked by the stdlib::gen_uart_loopback payload.
+    pub fn loopback_test(&self) -> bool {
+        let mcr = self.read(MCR);
+        self.write(MCR, mcr | MCR_LOOP);
+
+        // Drop anything that was already received
+        while self.getc().is_some() {}
+
+        let pattern = 0xA5;
+        self.putc(pattern);
+
+        let mut received = None;
+        for _ in 0..0x1_0000 {
+            received = self.getc();
+            if received.is_some() {
+                break;
+            }
+        }
+
+        self.write(MCR, mcr);
+        received == Some(pattern)
+    }
+
     pub fn putc(&self, c: u8) {
         // wait for space
         while !self.tx_empty() {